@@ -0,0 +1,403 @@
+use futures_util::future::BoxFuture;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt, fs,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tmdb_api::{
+    client::{reqwest::ReqwestExecutor, Client},
+    error::Error,
+    movie::{
+        credits::MovieCredits, details::MovieDetails, search::MovieSearch,
+        translations::MovieTranslations, Movie, MovieShort,
+    },
+    prelude::Command,
+};
+use tokio::time::{sleep, sleep_until};
+
+use crate::cache::Cache;
+use crate::config::HttpConfig;
+use crate::provider::MetadataProvider;
+use crate::resolve::Event;
+use crate::structs::{Details, MovieEntry, Translation};
+
+// Errors while getting data from TMDB
+#[derive(Debug)]
+pub enum ApiError {
+    // The data isn't in the cache, and we're not allowed to ask TMDB
+    Offline,
+    // The provider doesn't know about the movie
+    NotFound,
+    Request(Error),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Offline => write!(f, "not found in the cache while offline"),
+            ApiError::NotFound => write!(f, "no such movie"),
+            ApiError::Request(e) => write!(f, "{}", describe(e)),
+        }
+    }
+}
+
+// Create the HTTP client as per the settings, which is shared by everything
+// that talks to TMDB
+pub fn http_client(http: &HttpConfig) -> Result<reqwest::Client, String> {
+    let mut http_client =
+        reqwest::Client::builder().timeout(Duration::from_secs(http.timeout_secs));
+    if let Some(proxy) = &http.proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).map_err(|e| format!("Invalid proxy '{proxy}': {e}"))?;
+        http_client = http_client.proxy(proxy);
+    }
+    if let Some(ca_bundle) = &http.ca_bundle {
+        let contents = fs::read(ca_bundle)
+            .map_err(|e| format!("Unable to read the CA bundle {ca_bundle}: {e}"))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&contents)
+            .map_err(|e| format!("Invalid CA bundle {ca_bundle}: {e}"))?;
+        for certificate in certificates {
+            http_client = http_client.add_root_certificate(certificate);
+        }
+    }
+    http_client
+        .build()
+        .map_err(|e| format!("Unable to set up the HTTP client: {}", root_cause(&e)))
+}
+
+// Create the client for the TMDB API
+pub fn client(
+    api_key: String,
+    http_client: reqwest::Client,
+    http: &HttpConfig,
+) -> Result<Client<ReqwestExecutor>, String> {
+    let mut builder = Client::<ReqwestExecutor>::builder()
+        .with_executor(ReqwestExecutor::from(http_client))
+        .with_api_key(api_key);
+    if let Some(base_url) = &http.base_url {
+        builder = builder.with_base_url(base_url.trim_end_matches('/').to_string());
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+// Spaces out the requests to TMDB, shared by everything that talks to it
+pub struct RateLimiter {
+    interval: Duration,
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: u32) -> RateLimiter {
+        RateLimiter {
+            interval: Duration::from_secs(1) / requests_per_second.max(1),
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    // Wait until the next request is allowed
+    async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        sleep_until(slot.into()).await;
+    }
+}
+
+// Client for the TMDB API, which goes through the cache
+pub struct Tmdb {
+    client: Client<ReqwestExecutor>,
+    cache: Cache,
+    limiter: RateLimiter,
+    // Number of times a failed request is tried again
+    max_retries: u32,
+    // Responses already seen in this run, so that they're not fetched twice
+    // even if the cache is disabled
+    memo: Mutex<HashMap<String, serde_json::Value>>,
+    on_event: Box<dyn Fn(Event) + Send + Sync>,
+}
+
+impl Tmdb {
+    pub fn new(
+        client: Client<ReqwestExecutor>,
+        cache: Cache,
+        limiter: RateLimiter,
+        max_retries: u32,
+    ) -> Tmdb {
+        Tmdb {
+            client,
+            cache,
+            limiter,
+            max_retries,
+            memo: Mutex::new(HashMap::new()),
+            on_event: Box::new(|_| {}),
+        }
+    }
+
+    // Get called back when a request is tried again
+    pub fn with_events(mut self, on_event: impl Fn(Event) + Send + Sync + 'static) -> Tmdb {
+        self.on_event = Box::new(on_event);
+        self
+    }
+
+    // Search for movies with the given title
+    pub async fn search(
+        &self,
+        query: &str,
+        year: Option<u16>,
+        language: Option<String>,
+        region: Option<String>,
+    ) -> Result<Vec<MovieShort>, ApiError> {
+        let key = format!(
+            "search-{query}-{}-{}-{}",
+            year.map(|y| y.to_string()).unwrap_or_default(),
+            language.clone().unwrap_or_default(),
+            region.clone().unwrap_or_default()
+        );
+        let search = &MovieSearch::new(query.to_string())
+            .with_year(year)
+            .with_language(language)
+            .with_region(region);
+        let client = &self.client;
+        self.cached(&key, move || async move {
+            Ok(search.execute(client).await?.results)
+        })
+        .await
+    }
+
+    // Get the names of the director(s) of a movie
+    pub async fn directors(&self, id: u64) -> Result<Vec<String>, ApiError> {
+        let credits_search = &MovieCredits::new(id);
+        let client = &self.client;
+        self.cached(&format!("directors-{id}"), move || async move {
+            let mut crew = credits_search.execute(client).await?.crew;
+            // Only keep the director(s)
+            crew.retain(|x| x.job == *"Director");
+            Ok(crew.into_iter().map(|x| x.person.name).collect())
+        })
+        .await
+    }
+
+    // Get the full details of a movie
+    pub async fn details(&self, id: u64, language: Option<String>) -> Result<Movie, ApiError> {
+        let key = format!("details-{id}-{}", language.clone().unwrap_or_default());
+        let details_search = &MovieDetails::new(id).with_language(language);
+        let client = &self.client;
+        self.cached(&key, move || details_search.execute(client))
+            .await
+    }
+
+    // Get the titles of a movie in all the languages
+    pub async fn translations(&self, id: u64) -> Result<Vec<Translation>, ApiError> {
+        let translations_search = &MovieTranslations::new(id);
+        let client = &self.client;
+        let translations = self
+            .cached(&format!("translations-{id}"), move || async move {
+                Ok(translations_search.execute(client).await?.translations)
+            })
+            .await?;
+        Ok(translations
+            .into_iter()
+            .map(|t| Translation {
+                language: t.iso_639_1,
+                region: t.iso_3166_1,
+                title: t.data.title.unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    // Get the TMDB ids of the movies with the given IMDb id
+    pub async fn find_imdb_id(&self, imdb_id: &str) -> Result<Vec<u64>, ApiError> {
+        let find = &FindByImdbId {
+            imdb_id: imdb_id.to_string(),
+        };
+        let client = &self.client;
+        self.cached(&format!("find-{imdb_id}"), move || async move {
+            let results = find.execute(client).await?.movie_results;
+            Ok(results.into_iter().map(|m| m.inner.id).collect())
+        })
+        .await
+    }
+
+    // Use the cached value if possible, otherwise fetch and store it
+    async fn cached<T, F, Fut>(&self, key: &str, fetch: F) -> Result<T, ApiError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let memoized = self.memo.lock().unwrap().get(key).cloned();
+        if let Some(value) = memoized.and_then(|v| serde_json::from_value(v).ok()) {
+            return Ok(value);
+        }
+
+        let value = match self.cache.get(key) {
+            Some(value) => value,
+            None if self.cache.offline => return Err(ApiError::Offline),
+            None => {
+                let value = self.fetch(fetch).await.map_err(ApiError::Request)?;
+                self.cache.put(key, &value);
+                value
+            }
+        };
+        if let Ok(json) = serde_json::to_value(&value) {
+            self.memo.lock().unwrap().insert(key.to_string(), json);
+        }
+        Ok(value)
+    }
+
+    // Send a request, trying again with exponential backoff if it fails for
+    // reasons that are likely to go away, like timeouts or being rate limited
+    async fn fetch<T, F, Fut>(&self, fetch: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 0;
+        loop {
+            self.limiter.wait().await;
+            match fetch().await {
+                Err(e) if attempt < self.max_retries && is_transient(&e) => {
+                    let delay = Duration::from_millis(500) * 2_u32.pow(attempt);
+                    attempt += 1;
+                    (self.on_event)(Event::Retry {
+                        attempt,
+                        delay,
+                        error: &ApiError::Request(e),
+                    });
+                    sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl MetadataProvider for Tmdb {
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        year: Option<u16>,
+        language: Option<String>,
+        region: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<MovieEntry>, ApiError>> {
+        Box::pin(async move {
+            let results = Tmdb::search(self, query, year, language, region).await?;
+            Ok(results.into_iter().map(MovieEntry::from).collect())
+        })
+    }
+
+    fn directors(&self, id: u64) -> BoxFuture<'_, Result<Vec<String>, ApiError>> {
+        Box::pin(Tmdb::directors(self, id))
+    }
+
+    fn details(
+        &self,
+        id: u64,
+        language: Option<String>,
+    ) -> BoxFuture<'_, Result<Details, ApiError>> {
+        Box::pin(async move { Ok(Details::from(Tmdb::details(self, id, language).await?)) })
+    }
+
+    fn translations(&self, id: u64) -> BoxFuture<'_, Result<Vec<Translation>, ApiError>> {
+        Box::pin(Tmdb::translations(self, id))
+    }
+
+    fn movie(
+        &self,
+        id: u64,
+        language: Option<String>,
+    ) -> BoxFuture<'_, Result<MovieEntry, ApiError>> {
+        Box::pin(async move {
+            let movie = Tmdb::details(self, id, language).await?;
+            let mut entry = MovieEntry::from(MovieShort {
+                inner: movie.inner.clone(),
+                genre_ids: Vec::new(),
+            });
+            entry.add_details(Details::from(movie));
+            Ok(entry)
+        })
+    }
+
+    fn find_imdb_id<'a>(&'a self, imdb_id: &'a str) -> BoxFuture<'a, Result<u64, ApiError>> {
+        Box::pin(async move {
+            let ids = Tmdb::find_imdb_id(self, imdb_id).await?;
+            ids.into_iter().next().ok_or(ApiError::NotFound)
+        })
+    }
+}
+
+// Command for finding movies by their IMDb id, which tmdb_api doesn't have
+struct FindByImdbId {
+    imdb_id: String,
+}
+
+#[derive(Deserialize)]
+struct FindResult {
+    movie_results: Vec<MovieShort>,
+}
+
+impl Command for FindByImdbId {
+    type Output = FindResult;
+
+    fn path(&self) -> Cow<'static, str> {
+        Cow::Owned(format!("/find/{}", self.imdb_id))
+    }
+
+    fn params(&self) -> Vec<(&'static str, Cow<'_, str>)> {
+        vec![("external_source", Cow::Borrowed("imdb_id"))]
+    }
+}
+
+// Check if a failed request is worth trying again
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::Request { source } => match source.downcast_ref::<reqwest::Error>() {
+            Some(e) => e.is_timeout() || e.is_connect() || e.is_request(),
+            None => true,
+        },
+        // Error pages from proxies and load balancers can't be parsed
+        Error::Response { .. } => true,
+        Error::Server { code, .. } => *code == 429 || *code >= 500,
+        Error::Validation(_) => false,
+    }
+}
+
+// Describe what went wrong with a request, since the errors from tmdb_api
+// don't include the cause in their message
+fn describe(error: &Error) -> String {
+    match error {
+        Error::Request { source } => match source.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_timeout() => String::from("the request timed out"),
+            Some(e) if e.is_connect() => format!("could not connect to TMDB: {}", root_cause(e)),
+            _ => format!("the request failed: {}", root_cause(source.as_ref())),
+        },
+        Error::Response { source } => {
+            format!(
+                "could not read the response: {}",
+                root_cause(source.as_ref())
+            )
+        }
+        Error::Validation(e) => format!("the request was rejected: {}", e.errors.join(", ")),
+        Error::Server { code: 401, .. } => String::from("the API key was rejected (HTTP 401)"),
+        Error::Server { code: 429, .. } => String::from("rate limited by TMDB (HTTP 429)"),
+        Error::Server { code, content } => {
+            format!("TMDB returned HTTP {code}: {}", content.status_message)
+        }
+    }
+}
+
+// Get the innermost cause of an error, which usually has the useful message
+pub(crate) fn root_cause(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut error = error;
+    while let Some(source) = error.source() {
+        error = source;
+    }
+    error.to_string()
+}
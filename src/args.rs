@@ -8,14 +8,80 @@ pub fn get_command() -> Command {
         .author("Sayantan Santra <sayantan.santra@gmail.com>")
         .about("A simple tool to rename movies, written in Rust.")
         .arg(arg!(-d --directory "Run in directory mode").action(ArgAction::SetTrue))
+        .arg(
+            arg!(-r --recursive "Process a whole library, recursing into directories")
+                .action(ArgAction::SetTrue),
+        )
         .arg(arg!(-n --"dry-run" "Do a dry run").action(ArgAction::SetTrue))
         .arg(arg!(-l --"i-feel-lucky" "Always choose the first option").action(ArgAction::SetTrue))
+        .arg(arg!(-o --offline "Only use the cached results from TMDB").action(ArgAction::SetTrue))
+        .arg(arg!(-j --jobs <N> "Number of files to look up at the same time"))
+        .arg(arg!(
+            -a --"auto-threshold" <SCORE>
+            "Automatically choose the best match if its confidence (between 0 and 1) is at least this"
+        ))
+        .arg(
+            arg!(--plan <FILE> "Write the candidates for every file to a plan, without renaming")
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            arg!(--apply <FILE> "Rename the files as per the choices in a plan")
+                .value_hint(ValueHint::FilePath)
+                .conflicts_with("plan"),
+        )
+        .arg(
+            arg!(--"tmdb-id" <ID> "Use the movie with this TMDB id instead of searching")
+                .conflicts_with_all(["directory", "recursive", "plan", "apply"]),
+        )
+        .arg(
+            arg!(--"imdb-id" <ID> "Use the movie with this IMDb id instead of searching")
+                .conflicts_with_all(["tmdb-id", "directory", "recursive", "plan", "apply"]),
+        )
+        .arg(
+            arg!(--nfo "Write an NFO file with the details of every renamed movie")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["plan", "apply"]),
+        )
+        .arg(
+            arg!(--artwork "Download the poster and fanart of every renamed movie")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["plan", "apply", "offline"]),
+        )
+        .arg(
+            arg!(--"library-root" <DIR> "Move the files into this library, as per the folder pattern")
+                .value_hint(ValueHint::DirPath)
+                .conflicts_with("apply"),
+        )
+        .arg(
+            arg!(--"on-conflict" <POLICY> "What to do when the new name is already taken")
+                .value_parser([
+                    "skip",
+                    "overwrite",
+                    "suffix",
+                    "ask",
+                    "keep-larger",
+                    "keep-better-quality",
+                ]),
+        )
+        .arg(
+            arg!(-m --mode <MODE> "How the files get their new names")
+                .value_parser(["rename", "move", "copy", "hardlink", "symlink", "reflink"]),
+        )
+        .arg(arg!(-p --profile <NAME> "Use the settings from a profile in the config file"))
+        .arg(
+            arg!(-u --undo [RUN_ID] "Undo the renames done in a run (defaults to the last run)")
+                .num_args(0..=1)
+                // Otherwise `-u file.mkv` would take the file as the run id
+                .require_equals(true)
+                .default_missing_value("latest")
+                .conflicts_with("entries"),
+        )
         .arg(
             arg!([entries] "The files/directories to be processed")
                 .trailing_var_arg(true)
                 .num_args(1..)
                 .value_hint(ValueHint::AnyPath)
-                .required(true),
+                .required_unless_present_any(["undo", "apply"]),
         )
         // Use -v instead of -V for version
         .disable_version_flag(true)
@@ -28,23 +94,29 @@ pub fn get_command() -> Command {
 }
 
 // Function to process the passed arguments
-pub fn process_args() -> (Vec<String>, HashMap<String, bool>) {
+pub fn process_args() -> (Vec<String>, HashMap<String, bool>, HashMap<String, String>) {
     let matches = get_command().get_matches();
 
-    // Generate the settings HashMap from read flags
+    // Generate the settings HashMap from read flags, and the options HashMap
+    // from the arguments that take a value
     let mut settings = HashMap::new();
+    let mut options = HashMap::new();
     for id in matches.ids().map(|x| x.as_str()) {
-        if id != "entries" {
-            settings.insert(id.to_string(), matches.get_flag(id));
+        if id == "entries" {
+            continue;
+        }
+        if let Ok(Some(flag)) = matches.try_get_one::<bool>(id) {
+            settings.insert(id.to_string(), *flag);
+        } else if let Ok(Some(value)) = matches.try_get_one::<String>(id) {
+            options.insert(id.to_string(), value.clone());
         }
     }
 
     // Every unmatched argument should be treated as a file entry
     let entries: Vec<String> = matches
         .get_many::<String>("entries")
-        .expect("No entries provided!")
-        .cloned()
-        .collect();
+        .map(|e| e.cloned().collect())
+        .unwrap_or_default();
 
-    (entries, settings)
+    (entries, settings, options)
 }
@@ -0,0 +1,51 @@
+use crate::api::root_cause;
+use crate::config::ArtworkConfig;
+use crate::structs::MovieEntry;
+
+// Downloads the posters and fanart of movies from TMDB
+pub struct Artwork {
+    client: reqwest::Client,
+    base_url: String,
+    poster_size: String,
+    fanart_size: String,
+}
+
+impl Artwork {
+    pub fn new(client: reqwest::Client, config: &ArtworkConfig) -> Artwork {
+        Artwork {
+            client,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            poster_size: config.poster_size.clone(),
+            fanart_size: config.fanart_size.clone(),
+        }
+    }
+
+    // Get the kinds of the images a movie has, "poster" and "fanart", along
+    // with their URLs in the configured sizes
+    pub fn images(&self, movie: &MovieEntry) -> Vec<(&'static str, String)> {
+        [
+            ("poster", &movie.poster_path, &self.poster_size),
+            ("fanart", &movie.backdrop_path, &self.fanart_size),
+        ]
+        .into_iter()
+        .filter_map(|(kind, path, size)| {
+            let path = path.as_ref()?;
+            Some((kind, format!("{}/{size}{path}", self.base_url)))
+        })
+        .collect()
+    }
+
+    // Download an image, which is only written to a file once the whole image has
+    // been received
+    pub async fn download(&self, url: &str) -> Result<Vec<u8>, String> {
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| root_cause(&e))?;
+        let bytes = response.bytes().await.map_err(|e| root_cause(&e))?;
+        Ok(bytes.to_vec())
+    }
+}
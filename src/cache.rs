@@ -0,0 +1,108 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::CacheConfig;
+
+// On-disk cache of the responses from TMDB
+pub struct Cache {
+    dir: PathBuf,
+    enabled: bool,
+    ttl: u64,
+    pub offline: bool,
+}
+
+// What gets stored in a cache file
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    fetched_at: u64,
+    value: T,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig, offline: bool) -> Cache {
+        // There's no cache without a place to keep it
+        let dir = cache_dir();
+        Cache {
+            enabled: config.enabled && dir.is_some(),
+            dir: dir.unwrap_or_default(),
+            ttl: config.ttl_days * 24 * 60 * 60,
+            offline,
+        }
+    }
+
+    // Get a cached value, if it's there and hasn't expired. In offline mode,
+    // expired values are used too, since there's nothing better.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        if !self.enabled {
+            return None;
+        }
+        let contents = fs::read_to_string(self.path(key)).ok()?;
+        let entry: CacheEntry<T> = serde_json::from_str(&contents).ok()?;
+        if self.offline || now().saturating_sub(entry.fetched_at) < self.ttl {
+            Some(entry.value)
+        } else {
+            None
+        }
+    }
+
+    // Store a value in the cache, failures are ignored since the cache is optional
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) {
+        if !self.enabled {
+            return;
+        }
+        let entry = CacheEntry {
+            fetched_at: now(),
+            value,
+        };
+        if let Ok(contents) = serde_json::to_string(&entry) {
+            fs::create_dir_all(&self.dir).ok();
+            fs::write(self.path(key), contents).ok();
+        }
+    }
+
+    // Get the file for a key, keeping only the characters that are safe in filenames,
+    // along with a hash so that different keys never end up in the same file
+    fn path(&self, key: &str) -> PathBuf {
+        let mut name: String = key
+            .to_lowercase()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        name.truncate(100);
+        // FNV-1a, which unlike the std hasher is stable between releases
+        let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        self.dir.join(format!("{name}-{hash:016x}.json"))
+    }
+}
+
+// Current time in seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// Get the directory where the cache is stored, None if there's no absolute path
+// for it, so that it doesn't end up inside the current directory
+pub fn cache_dir() -> Option<PathBuf> {
+    let cache_home = match env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".cache"),
+    };
+    cache_home
+        .is_absolute()
+        .then(|| cache_home.join("movie-rename"))
+}
@@ -0,0 +1,436 @@
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs, path::PathBuf};
+
+// What to do when the destination of a rename already exists
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    // Add a number to the new name, like "Apur Sansar (1959) (1).mkv"
+    Suffix,
+    // Ask what to do every time, skipping when there's nobody to ask
+    Ask,
+    // Keep whichever of the two files is larger
+    KeepLarger,
+    // Keep the file with the higher resolution and source as per their names,
+    // or the larger one if that can't be told
+    KeepBetterQuality,
+}
+
+impl ConflictPolicy {
+    // Get the policy from its name, as used in the config and the flags
+    pub fn parse(name: &str) -> Option<ConflictPolicy> {
+        ConflictPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Suffix => "suffix",
+            ConflictPolicy::Ask => "ask",
+            ConflictPolicy::KeepLarger => "keep-larger",
+            ConflictPolicy::KeepBetterQuality => "keep-better-quality",
+        }
+    }
+
+    pub const ALL: [ConflictPolicy; 6] = [
+        ConflictPolicy::Skip,
+        ConflictPolicy::Overwrite,
+        ConflictPolicy::Suffix,
+        ConflictPolicy::Ask,
+        ConflictPolicy::KeepLarger,
+        ConflictPolicy::KeepBetterQuality,
+    ];
+}
+
+// How the files get their new names
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    // Rename the files, which doesn't work across filesystems
+    Rename,
+    // Rename the files, or copy and delete them if they're on another filesystem
+    Move,
+    // Leave the files alone, and create new ones with the new names
+    Copy,
+    Hardlink,
+    Symlink,
+    // Copy on write clones, which share the data until one of them is changed
+    Reflink,
+}
+
+impl Mode {
+    // Whether the original files are left as they are
+    pub fn keeps_source(self) -> bool {
+        !matches!(self, Mode::Rename | Mode::Move)
+    }
+
+    // Get the mode from its name, as used in the config and the flags
+    pub fn parse(name: &str) -> Option<Mode> {
+        Mode::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Rename => "rename",
+            Mode::Move => "move",
+            Mode::Copy => "copy",
+            Mode::Hardlink => "hardlink",
+            Mode::Symlink => "symlink",
+            Mode::Reflink => "reflink",
+        }
+    }
+
+    pub const ALL: [Mode; 6] = [
+        Mode::Rename,
+        Mode::Move,
+        Mode::Copy,
+        Mode::Hardlink,
+        Mode::Symlink,
+        Mode::Reflink,
+    ];
+}
+
+// Where the movie data comes from
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProviderKind {
+    Tmdb,
+    // Movies listed in a JSON file, see the fixture key
+    Fixture,
+}
+
+// Settings for the on-disk cache of TMDB responses
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub ttl_days: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            ttl_days: 7,
+        }
+    }
+}
+
+// Settings for talking to TMDB over HTTP
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    // Where the TMDB API is, for using a mirror instead of the public one
+    pub base_url: Option<String>,
+    // Proxy for all the requests, which can have the credentials in it
+    pub proxy: Option<String>,
+    // Seconds to wait for a response before giving up on a request
+    pub timeout_secs: u64,
+    // PEM file with extra certificates to trust, like the one of a proxy
+    pub ca_bundle: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            base_url: None,
+            proxy: None,
+            timeout_secs: 30,
+            ca_bundle: None,
+        }
+    }
+}
+
+// Settings for downloading the posters and fanart with --artwork
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArtworkConfig {
+    // Where the TMDB images are
+    pub base_url: String,
+    // Sizes of the images, as named by TMDB
+    pub poster_size: String,
+    pub fanart_size: String,
+}
+
+impl Default for ArtworkConfig {
+    fn default() -> Self {
+        ArtworkConfig {
+            base_url: String::from("https://image.tmdb.org/t/p"),
+            poster_size: String::from("w780"),
+            fanart_size: String::from("w1280"),
+        }
+    }
+}
+
+// The image sizes TMDB has for posters and backdrops
+pub const POSTER_SIZES: [&str; 7] = ["w92", "w154", "w185", "w342", "w500", "w780", "original"];
+pub const FANART_SIZES: [&str; 4] = ["w300", "w780", "w1280", "original"];
+
+// Named set of overrides, selected using --profile
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub pattern: Option<String>,
+    pub language: Option<String>,
+    pub region: Option<String>,
+    pub extensions: Option<Vec<String>>,
+    pub on_conflict: Option<ConflictPolicy>,
+    pub mode: Option<Mode>,
+    pub library_root: Option<String>,
+    pub folder_pattern: Option<String>,
+}
+
+// Contents of the config file
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub provider: ProviderKind,
+    // JSON file with the movies, for the fixture provider
+    pub fixture: Option<String>,
+    pub api_key: String,
+    pub pattern: String,
+    // Language of the titles, like "de" or "de-AT"
+    pub language: Option<String>,
+    // Country for the release dates, like "DE"
+    pub region: Option<String>,
+    pub extensions: Vec<String>,
+    pub on_conflict: ConflictPolicy,
+    pub mode: Mode,
+    // Directory the files are moved into, instead of being renamed in place
+    pub library_root: Option<String>,
+    // Pattern for the directories inside the library root, which can have / in it
+    pub folder_pattern: String,
+    // Name the NFO files movie.nfo when the movie is alone in its directory, instead
+    // of naming them after the movie
+    pub movie_nfo: bool,
+    // Number of requests for credits that are done at the same time
+    pub credits_concurrency: usize,
+    // Only get the credits for the chosen movie, so directors aren't shown in the menu
+    pub lazy_credits: bool,
+    // Highest number of requests sent to TMDB every second
+    pub requests_per_second: u32,
+    // Number of times a request is tried again after a timeout or a server error
+    pub max_retries: u32,
+    pub cache: CacheConfig,
+    pub http: HttpConfig,
+    pub artwork: ArtworkConfig,
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            provider: ProviderKind::Tmdb,
+            fixture: None,
+            api_key: String::new(),
+            pattern: String::from("{title} ({year}) - {director}"),
+            language: None,
+            region: None,
+            extensions: ["mp4", "avi", "mkv", "flv", "m4a", "srt", "ssa"]
+                .map(String::from)
+                .to_vec(),
+            on_conflict: ConflictPolicy::Skip,
+            mode: Mode::Move,
+            library_root: None,
+            folder_pattern: String::from("{title} ({year})"),
+            movie_nfo: false,
+            credits_concurrency: 8,
+            lazy_credits: false,
+            requests_per_second: 40,
+            max_retries: 4,
+            cache: CacheConfig::default(),
+            http: HttpConfig::default(),
+            artwork: ArtworkConfig::default(),
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    // Read the config file, preferring config.toml over the legacy config file
+    pub fn load(profile: Option<&str>) -> Result<Config, String> {
+        let dir = config_dir();
+        let toml_file = dir.join("config.toml");
+        let legacy_file = dir.join("config");
+
+        let mut config = if toml_file.is_file() {
+            Config::from_toml(&read(&toml_file)?)
+                .map_err(|e| format!("Error parsing {}:\n{e}", toml_file.display()))?
+        } else if legacy_file.is_file() {
+            let contents = read(&legacy_file)?;
+            // The legacy file has the bare API key on its first line, so if it
+            // looks like TOML instead, treat it that way
+            let first_line = contents.lines().find(|l| !l.trim().is_empty());
+            if first_line.is_some_and(|l| l.contains('=') || l.trim_start().starts_with('[')) {
+                Config::from_toml(&contents)
+            } else {
+                Config::from_legacy(&contents)
+            }
+            .map_err(|e| format!("Error parsing {}:\n{e}", legacy_file.display()))?
+        } else {
+            return Err(format!(
+                "Could not find the config file {}.",
+                toml_file.display()
+            ));
+        };
+
+        if let Some(name) = profile {
+            config.apply_profile(name)?;
+        }
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Parse the TOML config format
+    fn from_toml(contents: &str) -> Result<Config, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    // Parse the legacy config format, where the first line is the API key
+    // and the second line is the pattern. Brackets were copied into the names
+    // as they are back then, so they're escaped to keep them that way.
+    fn from_legacy(contents: &str) -> Result<Config, String> {
+        let mut lines = contents.lines();
+        let mut config = Config {
+            api_key: lines.next().unwrap_or("").trim().to_string(),
+            ..Default::default()
+        };
+        if let Some(pattern) = lines.next().filter(|p| !p.trim().is_empty()) {
+            config.pattern = pattern
+                .replace('\\', "\\\\")
+                .replace('[', "\\[")
+                .replace(']', "\\]");
+        }
+        if config.api_key.is_empty() {
+            return Err(String::from("line 1: the API key is missing"));
+        }
+        Ok(config)
+    }
+
+    // Override the settings with the ones from a profile
+    fn apply_profile(&mut self, name: &str) -> Result<(), String> {
+        let Some(profile) = self.profiles.remove(name) else {
+            return Err(format!(
+                "The profile '{name}' is not defined in the config file."
+            ));
+        };
+        if let Some(pattern) = profile.pattern {
+            self.pattern = pattern;
+        }
+        if profile.language.is_some() {
+            self.language = profile.language;
+        }
+        if profile.region.is_some() {
+            self.region = profile.region;
+        }
+        if let Some(extensions) = profile.extensions {
+            self.extensions = extensions;
+        }
+        if let Some(on_conflict) = profile.on_conflict {
+            self.on_conflict = on_conflict;
+        }
+        if let Some(mode) = profile.mode {
+            self.mode = mode;
+        }
+        if profile.library_root.is_some() {
+            self.library_root = profile.library_root;
+        }
+        if let Some(folder_pattern) = profile.folder_pattern {
+            self.folder_pattern = folder_pattern;
+        }
+        Ok(())
+    }
+
+    // Override the settings with the ones from the environment
+    fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(base_url) = env_var("MOVIE_RENAME_BASE_URL") {
+            self.http.base_url = Some(base_url);
+        }
+        if let Some(proxy) = env_var("MOVIE_RENAME_PROXY") {
+            self.http.proxy = Some(proxy);
+        }
+        if let Some(timeout) = env_var("MOVIE_RENAME_TIMEOUT") {
+            self.http.timeout_secs = timeout.parse().map_err(|_| {
+                format!("MOVIE_RENAME_TIMEOUT should be a number of seconds, not '{timeout}'.")
+            })?;
+        }
+        if let Some(ca_bundle) = env_var("MOVIE_RENAME_CA_BUNDLE") {
+            self.http.ca_bundle = Some(ca_bundle);
+        }
+        Ok(())
+    }
+
+    // Check for values that parse fine, but can't be used
+    fn validate(&self) -> Result<(), String> {
+        match self.provider {
+            ProviderKind::Tmdb if self.api_key.is_empty() => {
+                return Err(String::from("Could not read the API key."));
+            }
+            ProviderKind::Fixture if self.fixture.is_none() => {
+                return Err(String::from(
+                    "The fixture provider needs the fixture file to be set.",
+                ));
+            }
+            _ => {}
+        }
+        if self.pattern.trim().is_empty() {
+            return Err(String::from("The pattern can't be empty."));
+        }
+        if self.folder_pattern.trim().is_empty() {
+            return Err(String::from("The folder pattern can't be empty."));
+        }
+        if self.extensions.is_empty() {
+            return Err(String::from("The list of extensions can't be empty."));
+        }
+        if self.credits_concurrency == 0 {
+            return Err(String::from("credits_concurrency must be at least 1."));
+        }
+        if self.requests_per_second == 0 {
+            return Err(String::from("requests_per_second must be at least 1."));
+        }
+        if self.http.timeout_secs == 0 {
+            return Err(String::from("http.timeout_secs must be at least 1."));
+        }
+        if !POSTER_SIZES.contains(&self.artwork.poster_size.as_str()) {
+            return Err(format!(
+                "artwork.poster_size must be one of {}.",
+                POSTER_SIZES.join(", ")
+            ));
+        }
+        if !FANART_SIZES.contains(&self.artwork.fanart_size.as_str()) {
+            return Err(format!(
+                "artwork.fanart_size must be one of {}.",
+                FANART_SIZES.join(", ")
+            ));
+        }
+        if self.cache.enabled && self.cache.ttl_days == 0 {
+            return Err(String::from("cache.ttl_days must be at least 1."));
+        }
+        Ok(())
+    }
+}
+
+// Get an environment variable, if it's set to something
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+// Read a file into a string
+fn read(path: &PathBuf) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Error reading {}: {e}", path.display()))
+}
+
+// Get the directory where the config files are stored
+fn config_dir() -> PathBuf {
+    let config_home = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_home.join("movie-rename")
+}
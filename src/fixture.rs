@@ -0,0 +1,182 @@
+use futures_util::future::{self, BoxFuture};
+use serde::Deserialize;
+use std::{collections::HashMap, fs};
+
+use crate::api::ApiError;
+use crate::provider::MetadataProvider;
+use crate::structs::{get_long_lang, Details, MovieEntry, Translation};
+
+// Provider that reads the movies from a JSON file instead of asking TMDB,
+// so that everything can be tried out without a network
+pub struct Fixture {
+    movies: Vec<FixtureMovie>,
+}
+
+// A movie in the fixture file, only the id and the title are required
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FixtureMovie {
+    id: u64,
+    title: String,
+    original_title: Option<String>,
+    // In the YYYY-MM-DD format
+    release_date: Option<String>,
+    #[serde(default = "default_language")]
+    original_language: String,
+    #[serde(default)]
+    popularity: f64,
+    #[serde(default)]
+    directors: Vec<String>,
+    imdb_id: Option<String>,
+    collection: Option<String>,
+    runtime: Option<u64>,
+    overview: Option<String>,
+    #[serde(default)]
+    genres: Vec<String>,
+    poster_path: Option<String>,
+    backdrop_path: Option<String>,
+    // Titles in other languages, with keys like "de" or "de-AT"
+    #[serde(default)]
+    titles: HashMap<String, String>,
+}
+
+fn default_language() -> String {
+    String::from("en")
+}
+
+impl Fixture {
+    // Read the movies from a fixture file, which has a list of movies in it
+    pub fn load(path: &str) -> Result<Fixture, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read the fixture file {path}: {e}"))?;
+        let movies = serde_json::from_str(&contents)
+            .map_err(|e| format!("Error parsing the fixture file {path}: {e}"))?;
+        Ok(Fixture { movies })
+    }
+
+    fn find(&self, id: u64) -> Result<&FixtureMovie, ApiError> {
+        self.movies
+            .iter()
+            .find(|m| m.id == id)
+            .ok_or(ApiError::NotFound)
+    }
+}
+
+impl MetadataProvider for Fixture {
+    // Movies whose title contains the query match, ignoring the case, and if
+    // there's a year, it has to be the year of release
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        year: Option<u16>,
+        _language: Option<String>,
+        _region: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<MovieEntry>, ApiError>> {
+        let query = query.to_lowercase();
+        let results = self
+            .movies
+            .iter()
+            .filter(|m| {
+                [Some(&m.title), m.original_title.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .any(|t| t.to_lowercase().contains(&query))
+            })
+            .filter(|m| year.is_none_or(|y| m.year().as_deref() == Some(&y.to_string())))
+            .map(FixtureMovie::entry)
+            .collect();
+        Box::pin(future::ready(Ok(results)))
+    }
+
+    fn directors(&self, id: u64) -> BoxFuture<'_, Result<Vec<String>, ApiError>> {
+        let directors = self.find(id).map(|m| m.directors.clone());
+        Box::pin(future::ready(directors))
+    }
+
+    fn details(
+        &self,
+        id: u64,
+        _language: Option<String>,
+    ) -> BoxFuture<'_, Result<Details, ApiError>> {
+        let details = self.find(id).map(|m| Details {
+            imdb_id: m.imdb_id.clone(),
+            collection: m.collection.clone(),
+            runtime: m.runtime,
+            genres: m.genres.clone(),
+        });
+        Box::pin(future::ready(details))
+    }
+
+    fn translations(&self, id: u64) -> BoxFuture<'_, Result<Vec<Translation>, ApiError>> {
+        let translations = self.find(id).map(|m| {
+            m.titles
+                .iter()
+                .map(|(key, title)| {
+                    let (language, region) = key.split_once('-').unwrap_or((key, ""));
+                    Translation {
+                        language: language.to_string(),
+                        region: region.to_string(),
+                        title: title.clone(),
+                    }
+                })
+                .collect()
+        });
+        Box::pin(future::ready(translations))
+    }
+
+    fn movie(
+        &self,
+        id: u64,
+        language: Option<String>,
+    ) -> BoxFuture<'_, Result<MovieEntry, ApiError>> {
+        Box::pin(async move {
+            let mut entry = self.find(id)?.entry();
+            entry.add_details(self.details(id, language).await?);
+            Ok(entry)
+        })
+    }
+
+    fn find_imdb_id<'a>(&'a self, imdb_id: &'a str) -> BoxFuture<'a, Result<u64, ApiError>> {
+        let id = self
+            .movies
+            .iter()
+            .find(|m| m.imdb_id.as_deref() == Some(imdb_id))
+            .map(|m| m.id)
+            .ok_or(ApiError::NotFound);
+        Box::pin(future::ready(id))
+    }
+}
+
+impl FixtureMovie {
+    fn year(&self) -> Option<String> {
+        self.release_date
+            .as_ref()
+            .and_then(|d| d.get(..4))
+            .map(String::from)
+    }
+
+    // Create the entry as it'd come from a search
+    fn entry(&self) -> MovieEntry {
+        MovieEntry {
+            title: self.title.clone(),
+            localized_title: None,
+            original_title: self.original_title.clone().unwrap_or(self.title.clone()),
+            id: self.id,
+            imdb_id: None,
+            director: None,
+            directors: Vec::new(),
+            year: self.year(),
+            release_date: self.release_date.clone(),
+            language: get_long_lang(&self.original_language),
+            collection: None,
+            popularity: self.popularity,
+            runtime: None,
+            overview: self.overview.clone(),
+            genres: Vec::new(),
+            poster_path: self.poster_path.clone(),
+            backdrop_path: self.backdrop_path.clone(),
+            score: None,
+            has_details: false,
+        }
+    }
+}
@@ -1,31 +1,40 @@
+use futures_util::{stream, StreamExt};
 use inquire::{
     ui::{Color, IndexPrefix, RenderConfig, Styled},
-    InquireError, Select,
+    Select, Text,
 };
-use std::{collections::HashMap, fs, path::Path};
-use tmdb_api::{
-    client::{reqwest::ReqwestExecutor, Client},
-    movie::{credits::MovieCredits, search::MovieSearch},
-    prelude::Command,
+use movie_rename::{
+    artwork::Artwork,
+    config::ConflictPolicy,
+    journal::{self, Journal},
+    nfo,
+    provider::MetadataProvider,
+    resolve::{auto_choice, embedded_id, file_stem, Choice, Chooser, Event, ParsedFile, Resolver},
+    structs::{get_long_lang, Language, MediaInfo, MovieEntry, MovieId, Settings},
+    transfer,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    slice,
 };
-use torrent_name_parser::Metadata;
-
-use crate::structs::{get_long_lang, Language, MovieEntry};
 
 // Function to process movie entries
 pub async fn process_file(
     filename: &String,
-    tmdb: &Client<ReqwestExecutor>,
-    pattern: &str,
-    dry_run: bool,
-    lucky: bool,
-    movie_list: Option<&HashMap<String, Option<String>>>,
+    resolver: &Resolver<'_>,
+    settings: &Settings,
+    journal: &Journal,
+    artwork: Option<&Artwork>,
+    movie_list: Option<&HashMap<String, Option<(String, u64)>>>,
     // The last bool tells whether the entry should be added to the movie_list or not
-    // The first String is filename without extension, and the second String is
-    // new basename, if any.
-) -> (String, Option<String>, bool) {
-    // Set RenderConfig for the menu items
-    inquire::set_global_render_config(get_render_config());
+    // The first String is filename without extension, and the second element is
+    // new basename and TMDB id of the chosen movie, if any.
+) -> (String, Option<(String, u64)>, bool) {
+    let mut chooser = Prompt::new(settings);
 
     // Get the basename
     let mut file_base = String::from(filename);
@@ -36,28 +45,19 @@ pub async fn process_file(
             file_base = String::from(parts.1);
         }
     }
-
-    // Split the filename into parts for a couple of checks and some later use
-    let filename_parts: Vec<&str> = filename.rsplit('.').collect();
-    let filename_without_ext = if filename_parts.len() >= 3 && filename_parts[1].len() == 2 {
-        filename.rsplitn(3, '.').last().unwrap().to_string()
-    } else if let Some(name) = filename.rsplit_once('.') {
-        name.0.to_string()
-    } else {
-        filename.to_string()
-    };
+    let filename_without_ext = file_stem(filename);
 
     // Check if the filename (without extension) has already been processed
     // If yes, we'll use the older results
     let mut preprocessed = false;
-    let mut new_name_base = match movie_list {
-        None => String::new(),
+    let (mut new_name_base, mut movie_id) = match movie_list {
+        None => (String::new(), 0),
         Some(list) => {
             if list.contains_key(&filename_without_ext) {
                 preprocessed = true;
                 list[&filename_without_ext].clone().unwrap_or_default()
             } else {
-                String::new()
+                (String::new(), 0)
             }
         }
     };
@@ -69,148 +69,834 @@ pub async fn process_file(
     }
 
     // Parse the filename for metadata
-    let metadata = if let Ok(meta) = Metadata::from(file_base.as_str()) {
-        meta
-    } else {
+    let Some(file) = ParsedFile::new(filename) else {
         println!("  Could not parse filename for {file_base}...");
         return (filename_without_ext, None, false);
     };
 
     // Process only if it's a valid file format
-    let mut extension = metadata.extension().unwrap_or("").to_string();
-    if ["mp4", "avi", "mkv", "flv", "m4a", "srt", "ssa"].contains(&extension.as_str()) {
+    if settings.extensions.contains(&file.extension) {
         println!("  Processing {file_base}...");
+    } else if preprocessed {
+        // Extras like .nfo files sharing the name of an already processed movie
+        // are renamed along with it
+        println!("  Processing {file_base} along with related files...");
     } else {
         println!("  Ignoring {file_base}...");
         return (filename_without_ext, None, false);
     }
 
     // Only do the TMDb API stuff if it's not preprocessed
+    let mut chosen = None;
     if !preprocessed {
-        // Search using the TMDb API
-        let year = metadata.year().map(|y| y as u16);
-        let search = MovieSearch::new(metadata.title().to_string()).with_year(year);
-        let reply = search.execute(tmdb).await;
-
-        let results_reply = match reply {
-            Ok(res) => Ok(res.results),
-            Err(e) => {
-                eprintln!("  There was an error while searching {file_base}!");
-                Err(e)
+        // Use the movie given on the command line or found in the file, or search for it
+        let choice = match resolver.known_movie(&file).await {
+            Some(movie) => Some(movie),
+            None if settings.movie_id.is_some() => None,
+            None => {
+                let candidates = resolver.candidates(&file).await;
+                resolver.choose(&file, candidates, &mut chooser).await
             }
         };
+        // If nothing is found, or nothing is chosen, skip
+        let Some(choice) = choice else {
+            return (filename_without_ext, None, true);
+        };
 
-        let mut movie_list: Vec<MovieEntry> = Vec::new();
-        // Create movie entry from the result
-        if let Ok(results) = results_reply {
-            for result in results {
-                let mut movie_details = MovieEntry::from(result);
-                // Get director's name, if needed
-                if pattern.contains("{director}") {
-                    let credits_search = MovieCredits::new(movie_details.id);
-                    let credits_reply = credits_search.execute(tmdb).await;
-                    if let Ok(credits) = credits_reply {
-                        let mut crew = credits.crew;
-                        // Only keep the director(s)
-                        crew.retain(|x| x.job == *"Director");
-                        if !crew.is_empty() {
-                            let directors: Vec<String> =
-                                crew.iter().map(|x| x.person.name.clone()).collect();
-                            let mut directors_text = directors.join(", ");
-                            if let Some(pos) = directors_text.rfind(',') {
-                                directors_text.replace_range(pos..pos + 2, " and ");
-                            }
-                            movie_details.director = Some(directors_text);
-                        }
+        // Create the new name, inside the directory for the movie if moving into a library
+        new_name_base = resolver.base_name(&file, &choice);
+        if let Some(folder) = resolver.folder(&file, &choice).filter(|f| !f.is_empty()) {
+            new_name_base = format!("{folder}/{new_name_base}");
+        }
+        movie_id = choice.id;
+        chosen = Some(choice);
+    } else {
+        println!("  Using previous choice for related files...");
+    }
+
+    // Add extension and stuff to the new name, the language is added for subtitles
+    let extension = resolver.extension(&file, &mut chooser);
+    let mut new_name_with_ext = new_name_base.clone();
+    if !extension.is_empty() {
+        new_name_with_ext = format!("{new_name_with_ext}.{extension}");
+    }
+    // The files stay in their directory, unless they're moved into a library
+    let dest_root = settings.library_root.clone().unwrap_or(parent);
+    let mut new_name = new_name_with_ext.clone();
+    if !dest_root.is_empty() {
+        new_name = Path::new(&dest_root).join(new_name).display().to_string();
+    }
+
+    // Process the renaming
+    let mut renamed = true;
+    if *filename == new_name {
+        println!("  [file] '{file_base}' already has correct name.");
+    } else {
+        if settings.library_root.is_some() {
+            println!("  [file] '{file_base}' -> '{new_name}'");
+        } else {
+            println!("  [file] '{file_base}' -> '{new_name_with_ext}'");
+        }
+        // Only do the rename of --dry-run isn't passed
+        if !settings.dry_run {
+            match move_file(filename, &new_name, movie_id, settings, journal) {
+                // The related files and the NFO file follow the movie if it got a number
+                Ok(Some(name)) => {
+                    if let Some(suffix) = file_stem(&name).strip_prefix(&file_stem(&new_name)) {
+                        new_name_base.push_str(suffix);
                     }
                 }
-                movie_list.push(movie_details);
+                Ok(None) => renamed = false,
+                Err(e) => {
+                    eprintln!("  {e}");
+                    renamed = false;
+                }
             }
         }
+    }
 
-        // If nothing is found, skip
-        if movie_list.is_empty() {
-            eprintln!("  Could not find any entries matching {file_base}!");
-            return (filename_without_ext, None, true);
+    // Write the NFO file and the artwork next to the movie, subtitles don't get them
+    if let Some(movie) = chosen.filter(|_| renamed && !file.is_subtitle()) {
+        if settings.nfo {
+            write_nfo(&movie, &dest_root, &new_name_base, settings, journal);
+        }
+        if let Some(artwork) = artwork {
+            write_artwork(
+                artwork,
+                &movie,
+                &dest_root,
+                &new_name_base,
+                settings,
+                journal,
+            )
+            .await;
         }
+    }
+    (
+        filename_without_ext,
+        Some((new_name_base, movie_id)),
+        !preprocessed,
+    )
+}
+
+// Give a file its new name as per the mode, creating the directories for it if
+// needed. If the name is taken, the conflict policy decides what happens. Returns
+// the name the file ended up with, or None if it was skipped.
+pub fn move_file(
+    filename: &str,
+    new_name: &str,
+    movie_id: u64,
+    settings: &Settings,
+    journal: &Journal,
+) -> Result<Option<String>, String> {
+    if let Some(dir) = Path::new(new_name).parent() {
+        create_dirs(dir, journal)
+            .map_err(|e| format!("Unable to create the directory {}: {e}", dir.display()))?;
+    }
 
-        let choice = if lucky {
-            // Take first choice if in lucky mode
-            movie_list.into_iter().next().unwrap()
+    let mut policy = settings.on_conflict;
+    let mut target = new_name.to_string();
+    let mut replace = policy == ConflictPolicy::Overwrite;
+    let mut attempt = 0;
+    loop {
+        let result = transfer::transfer(
+            Path::new(filename),
+            Path::new(&target),
+            settings.mode,
+            replace,
+        );
+        match result {
+            Ok(mode) => {
+                if mode != settings.mode {
+                    println!(
+                        "  Could not create a {}, copied the file instead...",
+                        settings.mode.name()
+                    );
+                }
+                // The original is still there, so undoing only needs the new file removed
+                if mode.keeps_source() {
+                    check_journal(journal.record_created(&target, Some(movie_id)));
+                } else {
+                    check_journal(journal.record(filename, &target, Some(movie_id)));
+                }
+                return Ok(Some(target));
+            }
+            // The destination is only replaced once, in case it can't be, like a directory
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && !replace => {}
+            Err(e) => return Err(format!("Unable to {} the file: {e}", settings.mode.name())),
+        }
+
+        if policy == ConflictPolicy::Ask {
+            policy = ask_conflict(&target);
+        }
+        match policy {
+            ConflictPolicy::Skip | ConflictPolicy::Ask => {
+                eprintln!("  Destination file already exists, skipping...");
+                return Ok(None);
+            }
+            ConflictPolicy::Overwrite => replace = true,
+            ConflictPolicy::Suffix => {
+                attempt += 1;
+                target = suffixed(new_name, attempt);
+                println!("  Destination file already exists, trying '{target}' instead...");
+            }
+            ConflictPolicy::KeepLarger if file_size(filename) > file_size(&target) => {
+                println!("  Replacing the existing file, which is smaller...");
+                replace = true;
+            }
+            ConflictPolicy::KeepLarger => {
+                eprintln!("  The existing file is at least as large, skipping...");
+                return Ok(None);
+            }
+            ConflictPolicy::KeepBetterQuality if better_quality(filename, &target) => {
+                println!("  Replacing the existing file, which is of lower quality...");
+                replace = true;
+            }
+            ConflictPolicy::KeepBetterQuality => {
+                eprintln!("  The existing file is at least as good, skipping...");
+                return Ok(None);
+            }
+        }
+    }
+}
+
+// Tell the user if something couldn't be written to the journal, as it can't be
+// undone then
+fn check_journal(result: io::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("  Could not write to the journal: {e}");
+    }
+}
+
+// Create a directory along with its parents, recording the ones that didn't exist so
+// that undoing the run removes them again
+fn create_dirs(dir: &Path, journal: &Journal) -> io::Result<()> {
+    let missing: Vec<&Path> = dir
+        .ancestors()
+        .take_while(|d| !d.as_os_str().is_empty() && !d.exists())
+        .collect();
+    fs::create_dir_all(dir)?;
+    for dir in missing.iter().rev() {
+        check_journal(journal.record_created(&dir.display().to_string(), None));
+    }
+    Ok(())
+}
+
+// Ask what to do with a file whose new name is taken, which is skipping it when
+// there's nobody to ask
+fn ask_conflict(target: &str) -> ConflictPolicy {
+    if !io::stdin().is_terminal() {
+        return ConflictPolicy::Skip;
+    }
+    let options = vec!["Skip", "Overwrite", "Keep both"];
+    let message = format!("  '{target}' already exists, what should be done?");
+    match Select::new(&message, options).prompt() {
+        Ok("Overwrite") => ConflictPolicy::Overwrite,
+        Ok("Keep both") => ConflictPolicy::Suffix,
+        _ => ConflictPolicy::Skip,
+    }
+}
+
+// Add a number to a name, before the extension and the language of subtitles
+fn suffixed(name: &str, number: usize) -> String {
+    let stem = file_stem(name);
+    format!("{stem} ({number}){}", &name[stem.len()..])
+}
+
+fn file_size(path: &str) -> u64 {
+    fs::metadata(path).map(|m| m.len()).unwrap_or_default()
+}
+
+// Check if a file is of higher quality than an existing one, going by the resolution
+// and then the source in their names, or by their sizes if the names don't tell. The
+// name the existing file had before it was renamed is used if it's in the journal.
+fn better_quality(filename: &str, existing: &str) -> bool {
+    let info = |name: &str| {
+        ParsedFile::new(name)
+            .map(|file| MediaInfo::from(&file.metadata, &file.file_base))
+            .unwrap_or_default()
+    };
+    let new = info(filename);
+    let old = info(&journal::original_name(existing).unwrap_or(existing.to_string()));
+    let ranks = [
+        (new.resolution_rank(), old.resolution_rank()),
+        (
+            new.source_rank().map(u32::from),
+            old.source_rank().map(u32::from),
+        ),
+    ];
+    for (new_rank, old_rank) in ranks {
+        if let (Some(new_rank), Some(old_rank)) = (new_rank, old_rank) {
+            if new_rank != old_rank {
+                return new_rank > old_rank;
+            }
+        }
+    }
+    file_size(filename) > file_size(existing)
+}
+
+// Write an NFO file with the details of the movie, named after the movie file
+fn write_nfo(
+    movie: &MovieEntry,
+    dest_root: &str,
+    name_base: &str,
+    settings: &Settings,
+    journal: &Journal,
+) {
+    let (dir, name, alone) = movie_dir(dest_root, name_base, settings);
+    let nfo_name = if settings.movie_nfo && alone {
+        String::from("movie.nfo")
+    } else {
+        format!("{name}.nfo")
+    };
+    let path = Path::new(&dir).join(&nfo_name);
+
+    println!(
+        "  [nfo] '{}'",
+        Path::new(name_base).with_file_name(&nfo_name).display()
+    );
+    if settings.dry_run {
+        return;
+    }
+    // The NFO file being replaced is kept aside, so that undoing the run brings it back
+    if settings.on_conflict == ConflictPolicy::Overwrite && path.is_file() {
+        if let Err(e) = journal.backup(&path, Some(movie.id)) {
+            eprintln!("  Unable to replace the NFO file: {e}");
+            return;
+        }
+    }
+    match write_new(&path, nfo::render(movie).as_bytes()) {
+        Ok(_) => check_journal(journal.record_created(&path.display().to_string(), Some(movie.id))),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!("  NFO file already exists, skipping...")
+        }
+        Err(e) => eprintln!("  Unable to write the NFO file: {e}"),
+    }
+}
+
+// Write a file that doesn't exist yet, failing with AlreadyExists if it does, so that
+// a file created in the meantime isn't overwritten. A partly written file is removed.
+fn write_new(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    file.write_all(contents).inspect_err(|_| {
+        fs::remove_file(path).ok();
+    })
+}
+
+// Download the poster and the fanart of a movie, unless they're already there. They're
+// named poster.jpg and fanart.jpg if the movie is the only one in its directory, and
+// after the movie otherwise.
+async fn write_artwork(
+    artwork: &Artwork,
+    movie: &MovieEntry,
+    dest_root: &str,
+    name_base: &str,
+    settings: &Settings,
+    journal: &Journal,
+) {
+    let (dir, name_base, alone) = movie_dir(dest_root, name_base, settings);
+
+    for (kind, url) in artwork.images(movie) {
+        let extension = url
+            .rsplit('/')
+            .next()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|x| x.1)
+            .unwrap_or("jpg");
+        let image_name = if alone {
+            format!("{kind}.{extension}")
         } else {
-            // Choose from the possible entries
+            format!("{name_base}-{kind}.{extension}")
+        };
+        let path = Path::new(&dir).join(&image_name);
+        if path.exists() {
+            println!("  [artwork] '{image_name}' already exists, skipping...");
+            continue;
+        }
+
+        println!("  [artwork] '{image_name}'");
+        if settings.dry_run {
+            continue;
+        }
+        let image = match artwork.download(&url).await {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("  Unable to download the {kind}: {e}");
+                continue;
+            }
+        };
+        match write_new(&path, &image) {
+            Ok(_) => {
+                check_journal(journal.record_created(&path.display().to_string(), Some(movie.id)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                println!("  [artwork] '{image_name}' already exists, skipping...")
+            }
+            Err(e) => eprintln!("  Unable to save the {kind}: {e}"),
+        }
+    }
+}
+
+// Get the directory of a renamed movie and its name without the directory, along with
+// whether it's the only movie in that directory
+fn movie_dir(dest_root: &str, name_base: &str, settings: &Settings) -> (String, String, bool) {
+    let movie_path = Path::new(dest_root).join(name_base);
+    let dir = match movie_path.parent().map(|p| p.display().to_string()) {
+        Some(dir) if !dir.is_empty() => dir,
+        _ => String::from("."),
+    };
+    let name = movie_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let movie_count = list_files(&dir)
+        .unwrap_or_default()
+        .iter()
+        .filter(|f| file_kind(f, settings) == 0)
+        .count();
+    (dir, name, movie_count == 1)
+}
+
+// Asks the user in the terminal whenever something has to be chosen
+struct Prompt {
+    // Whether only the confident matches are chosen when there's nobody to ask
+    auto: bool,
+}
+
+impl Prompt {
+    fn new(settings: &Settings) -> Prompt {
+        // Set RenderConfig for the menu items
+        inquire::set_global_render_config(get_render_config());
+        Prompt {
+            auto: settings.auto_threshold.is_some(),
+        }
+    }
+
+    // Ask for the TMDB or IMDb id of the movie, until a valid one or nothing is entered
+    fn movie_id(&self, file: &str) -> Option<MovieId> {
+        loop {
+            let text = Text::new(
+                format!("  TMDB or IMDb ID for {file} (leave empty to go back):").as_str(),
+            )
+            .prompt()
+            .ok()?;
+            if text.trim().is_empty() {
+                return None;
+            }
+            match MovieId::parse(&text) {
+                Some(id) => return Some(id),
+                None => eprintln!("  '{}' is not a valid TMDB or IMDb ID!", text.trim()),
+            }
+        }
+    }
+}
+
+// An entry in the menu for choosing the movie
+#[derive(Clone)]
+enum MenuItem {
+    Movie(Box<MovieEntry>),
+    EnterId,
+    SearchText,
+}
+
+impl fmt::Display for MenuItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MenuItem::Movie(movie) => write!(f, "{movie}"),
+            MenuItem::EnterId => write!(f, "Enter ID manually…"),
+            MenuItem::SearchText => write!(f, "Search with different text…"),
+        }
+    }
+}
+
+impl Chooser for Prompt {
+    fn choose(&mut self, file: &str, candidates: Vec<MovieEntry>) -> Option<Choice> {
+        if self.auto && !io::stdin().is_terminal() {
+            // Nobody's there to choose, so skip
+            eprintln!("  Could not find a confident match for {file}, skipping...");
+            return None;
+        }
+
+        let mut items: Vec<MenuItem> = candidates
+            .into_iter()
+            .map(|movie| MenuItem::Movie(Box::new(movie)))
+            .collect();
+        items.extend([MenuItem::EnterId, MenuItem::SearchText]);
+
+        // Choose from the possible entries, going back to the menu if nothing is entered
+        loop {
             match Select::new(
-                format!("  Possible choices for {file_base}:").as_str(),
-                movie_list,
+                format!("  Possible choices for {file}:").as_str(),
+                items.clone(),
             )
             .prompt()
             {
-                Ok(movie) => movie,
+                Ok(MenuItem::Movie(movie)) => return Some(Choice::Movie(movie)),
+                Ok(MenuItem::EnterId) => {
+                    if let Some(id) = self.movie_id(file) {
+                        return Some(Choice::Id(id));
+                    }
+                }
+                Ok(MenuItem::SearchText) => {
+                    if let Some(text) = self.search_text(file) {
+                        return Some(Choice::Search(text));
+                    }
+                }
                 Err(error) => {
                     println!("  {error}");
-                    let flag = matches!(
-                        error,
-                        InquireError::OperationCanceled | InquireError::OperationInterrupted
-                    );
-                    return (filename_without_ext, None, flag);
+                    return None;
                 }
             }
-        };
+        }
+    }
 
-        // Create the new name
-        new_name_base = choice.rename_format(String::from(pattern));
-    } else {
-        println!("  Using previous choice for related files...");
+    fn search_text(&mut self, file: &str) -> Option<String> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        Text::new(
+            format!("  Search for {file} with different text (leave empty to cancel):").as_str(),
+        )
+        .prompt()
+        .ok()
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
     }
 
-    // Handle the case for subtitle files
-    if ["srt", "ssa"].contains(&extension.as_str()) {
-        // Try to detect if there's already language info in the filename, else ask user to choose
-        if filename_parts.len() >= 3 && filename_parts[1].len() == 2 {
-            println!(
-                "  Keeping language {} as detected in the subtitle file's extension...",
-                get_long_lang(filename_parts[1])
-            );
-            extension = format!("{}.{}", filename_parts[1], extension);
-        } else if lucky {
-            extension = format!("en.{extension}");
-        } else {
-            let lang_list = Language::generate_list();
-            let lang_choice =
-                Select::new("  Choose the language for the subtitle file:", lang_list)
-                    .prompt()
-                    .expect("  Invalid choice!");
-            if lang_choice.short != *"none" {
-                extension = format!("{}.{}", lang_choice.short, extension);
+    fn subtitle_language(&mut self, file: &str) -> Option<String> {
+        if !io::stdin().is_terminal() {
+            println!("  Could not ask for the language of {file}, leaving it out...");
+            return None;
+        }
+        let lang_list = Language::generate_list();
+        let lang_choice = Select::new("  Choose the language for the subtitle file:", lang_list)
+            .prompt()
+            .ok()?;
+        (lang_choice.short != *"none").then_some(lang_choice.short)
+    }
+}
+
+// Show what happened while resolving a file
+pub fn print_event(event: Event) {
+    match event {
+        Event::SearchFailed { file, error } => {
+            eprintln!("  There was an error while searching {file}: {error}")
+        }
+        Event::DetailsFailed { file, error } => {
+            eprintln!("  There was an error while fetching details for {file}: {error}")
+        }
+        Event::SearchRetry { query, .. } => {
+            println!("  Nothing found, searching for '{query}' instead...")
+        }
+        Event::IdFound { file, id } => println!("  Found {id} for {file}..."),
+        Event::LookupFailed { file, id, error } => {
+            eprintln!("  Could not get the movie with {id} for {file}: {error}")
+        }
+        Event::NoMatches { file } => eprintln!("  Could not find any entries matching {file}!"),
+        Event::AutoChosen { movie, .. } => println!(
+            "  Automatically chose {} with a match of {:.0}%...",
+            movie.title,
+            movie.score.unwrap_or_default() * 100.0
+        ),
+        Event::SubtitleLanguage { language, .. } => println!(
+            "  Keeping language {} as detected in the subtitle file's extension...",
+            get_long_lang(language)
+        ),
+        Event::Retry { delay, error, .. } => eprintln!(
+            "  Request to TMDB failed ({error}), retrying in {:.1}s...",
+            delay.as_secs_f64()
+        ),
+    }
+}
+
+// Look up the given files ahead of time, with a number of them being looked up
+// at the same time. The responses are kept by the TMDB client, so processing the
+// files one by one afterwards doesn't have to wait for them.
+pub async fn prefetch(
+    filenames: &[String],
+    provider: &dyn MetadataProvider,
+    settings: &Settings,
+    jobs: usize,
+) {
+    let mut files: Vec<ParsedFile> = filenames
+        .iter()
+        .filter_map(|filename| ParsedFile::new(filename))
+        .filter(|file| settings.extensions.contains(&file.extension))
+        .collect();
+    // The movie files come first, as their duration is used for scoring
+    files.sort_by_key(|file| file.is_subtitle());
+
+    // Files of the same movie, like its subtitles, only need to be looked up once.
+    // With an id given on the command line, every file is the same movie.
+    if settings.movie_id.is_some() {
+        files.truncate(1);
+    }
+    let mut seen = Vec::new();
+    files.retain(|file| {
+        let key = match embedded_id(&file.filename) {
+            Some(id) => (Some(id), String::new(), None),
+            None => (
+                None,
+                file.metadata.title().to_string(),
+                file.metadata.year(),
+            ),
+        };
+        let new = !seen.contains(&key);
+        if new {
+            seen.push(key);
+        }
+        new
+    });
+    if files.is_empty() {
+        return;
+    }
+    println!("Looking up {} movie(s) with {jobs} job(s)...", files.len());
+
+    // Errors are ignored here, they're reported when the files are processed.
+    // Everything is fetched the same way as when the files are processed, up to
+    // the details of the chosen movie if it's chosen without asking.
+    let resolver = Resolver::new(provider, settings);
+    stream::iter(files)
+        .map(|file| {
+            let resolver = &resolver;
+            async move {
+                // Movies with a known id are looked up directly, without searching
+                if resolver.known_movie(&file).await.is_some() || settings.movie_id.is_some() {
+                    return;
+                }
+                let mut candidates = resolver.candidates(&file).await;
+                let index = if settings.lucky {
+                    Some(0)
+                } else {
+                    auto_choice(&candidates, settings.auto_threshold)
+                };
+                if let Some(movie) = index.and_then(|i| candidates.get_mut(i)) {
+                    if settings.lazy_credits || movie.director.is_none() {
+                        resolver.fetch_directors(slice::from_mut(movie)).await;
+                    }
+                    resolver.fetch_details(movie, &file.file_base).await;
+                }
             }
+        })
+        .buffer_unordered(jobs)
+        .collect::<Vec<()>>()
+        .await;
+}
+
+// Function to process all the files inside a directory, and rename the directory
+// itself if it contains exactly one movie
+pub async fn process_directory(
+    entry: &str,
+    resolver: &Resolver<'_>,
+    settings: &Settings,
+    journal: &Journal,
+    artwork: Option<&Artwork>,
+    rename_dir: bool,
+) {
+    let mut movie_list = HashMap::new();
+
+    let Some(mut filename_list) = list_files(entry) else {
+        eprintln!("There was an error accessing the directory {entry}!");
+        return;
+    };
+
+    // In recursive mode, directories without any movies are skipped silently
+    let has_video = filename_list.iter().any(|f| file_kind(f, settings) == 0);
+    if settings.recursive && !has_video {
+        return;
+    }
+    println!("Processing files inside the directory {entry}...");
+
+    // Process the movies first, so that the choice is made for them, and the
+    // related subtitles and extras get the same name
+    filename_list.sort_by_key(|f| (file_kind(f, settings), f.clone()));
+    for filename in filename_list {
+        let (filename_without_ext, movie_name_temp, add_to_list) = process_file(
+            &filename,
+            resolver,
+            settings,
+            journal,
+            artwork,
+            Some(&movie_list),
+        )
+        .await;
+
+        if add_to_list {
+            movie_list.insert(filename_without_ext, movie_name_temp);
         }
     }
 
-    // Add extension and stuff to the new name
-    let mut new_name_with_ext = new_name_base.clone();
-    if !extension.is_empty() {
-        new_name_with_ext = format!("{new_name_with_ext}.{extension}");
+    // The files have their own directories in the library, so there's nothing to
+    // rename, and the directory has to stay as it is if the files are kept
+    if !rename_dir || settings.library_root.is_some() || settings.mode.keeps_source() {
+        return;
     }
-    let mut new_name = new_name_with_ext.clone();
-    if !parent.is_empty() {
-        new_name = format!("{parent}/{new_name}");
+    if movie_list.len() != 1 {
+        eprintln!("Could not determine how to rename the directory {entry}!");
+        return;
     }
 
-    // Process the renaming
-    if *filename == new_name {
-        println!("  [file] '{file_base}' already has correct name.");
-    } else {
-        println!("  [file] '{file_base}' -> '{new_name_with_ext}'");
-        // Only do the rename of --dry-run isn't passed
-        if !dry_run {
-            if !Path::new(new_name.as_str()).is_file() {
-                fs::rename(filename, new_name.as_str()).expect("  Unable to rename file!");
-            } else {
-                eprintln!("  Destination file already exists, skipping...");
+    // If the file was ignored, exit
+    let Some((name, movie_id)) = movie_list.into_values().next().unwrap() else {
+        eprintln!("Not renaming directory as only movie was skipped.");
+        return;
+    };
+
+    let entry_clean = entry.trim_end_matches('/');
+    let old_path = PathBuf::from(entry_clean);
+    let dir_base = old_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if dir_base == name {
+        println!("[directory] '{entry_clean}' already has correct name.");
+        return;
+    }
+
+    println!("[directory] '{entry_clean}' -> '{name}'");
+    if settings.dry_run {
+        return;
+    }
+    // Only empty directories can be overwritten, and the policies that compare the
+    // files don't apply, so those skip
+    let replace = settings.on_conflict == ConflictPolicy::Overwrite;
+    let mut new_path = old_path.with_file_name(&name);
+    let mut attempt = 0;
+    loop {
+        match transfer::rename(&old_path, &new_path, replace) {
+            Ok(_) => {
+                check_journal(journal.record(
+                    entry_clean,
+                    &new_path.display().to_string(),
+                    Some(movie_id),
+                ));
+                return;
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::AlreadyExists
+                    && settings.on_conflict == ConflictPolicy::Suffix =>
+            {
+                attempt += 1;
+                new_path = old_path.with_file_name(format!("{name} ({attempt})"));
+                println!(
+                    "Destination directory already exists, trying '{}' instead...",
+                    new_path.display()
+                );
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::AlreadyExists | io::ErrorKind::DirectoryNotEmpty
+                ) =>
+            {
+                eprintln!("Destination directory already exists, skipping...");
+                return;
+            }
+            Err(e) => {
+                eprintln!("Unable to rename the directory {entry_clean}: {e}");
+                return;
+            }
+        }
+    }
+}
+
+// Function to process a whole library, going through all the directories inside it
+pub async fn process_tree(
+    root: &str,
+    resolver: &Resolver<'_>,
+    settings: &Settings,
+    journal: &Journal,
+    artwork: Option<&Artwork>,
+) {
+    let root_clean = root.trim_end_matches('/');
+    let mut dir_list = Vec::new();
+    collect_dirs(Path::new(root_clean), &mut dir_list);
+
+    // The directories come after their subdirectories, so renaming them
+    // doesn't change the paths that are yet to be processed
+    for dir in dir_list {
+        let dir = dir.display().to_string();
+        // The root of the library is never renamed
+        let rename_dir = dir != root_clean;
+        process_directory(&dir, resolver, settings, journal, artwork, rename_dir).await;
+    }
+}
+
+// Get the files that are to be processed for the given entries, used when
+// the files are looked up without being renamed right away
+pub fn collect_files(entries: &[String], directory: bool, recursive: bool) -> Vec<String> {
+    let mut filename_list = Vec::new();
+    for entry in entries {
+        let dir_list = if recursive {
+            let mut dir_list = Vec::new();
+            collect_dirs(Path::new(entry.trim_end_matches('/')), &mut dir_list);
+            dir_list
+        } else if directory {
+            vec![PathBuf::from(entry)]
+        } else {
+            filename_list.push(entry.clone());
+            continue;
+        };
+
+        for dir in dir_list {
+            let dir = dir.display().to_string();
+            match list_files(&dir) {
+                Some(mut files) => {
+                    files.sort();
+                    filename_list.append(&mut files);
+                }
+                None => eprintln!("There was an error accessing the directory {dir}!"),
             }
         }
     }
-    (filename_without_ext, Some(new_name_base), !preprocessed)
+    filename_list
+}
+
+// List the files directly inside a directory
+fn list_files(dir: &str) -> Option<Vec<String>> {
+    let files_in_dir = fs::read_dir(dir).ok()?;
+    Some(
+        files_in_dir
+            .filter_map(|f| f.ok())
+            .filter(|f| f.file_type().is_ok_and(|t| t.is_file()))
+            .map(|f| f.path().display().to_string())
+            .collect(),
+    )
+}
+
+// Collect all the directories inside a directory, including itself, with the
+// subdirectories coming first
+fn collect_dirs(dir: &Path, dir_list: &mut Vec<PathBuf>) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        let mut subdirs: Vec<_> = entries
+            .filter_map(|f| f.ok())
+            // Symlinks aren't followed, to avoid loops
+            .filter(|f| f.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|f| f.path())
+            .collect();
+        subdirs.sort();
+        for subdir in subdirs {
+            collect_dirs(&subdir, dir_list);
+        }
+    } else {
+        eprintln!(
+            "There was an error accessing the directory {}!",
+            dir.display()
+        );
+    }
+    dir_list.push(dir.to_path_buf());
+}
+
+// Sort key for files inside a directory: 0 for movies, 1 for subtitles and 2 for the rest
+fn file_kind(filename: &str, settings: &Settings) -> u8 {
+    let extension = filename.rsplit_once('.').map(|x| x.1).unwrap_or_default();
+    if ["srt", "ssa"].contains(&extension) {
+        1
+    } else if settings.extensions.iter().any(|e| e == extension) {
+        0
+    } else {
+        2
+    }
 }
 
 // RenderConfig for the menu items
@@ -0,0 +1,349 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{self, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::transfer;
+
+// A single rename that was performed during a run
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub old: String,
+    pub new: String,
+    pub tmdb_id: Option<u64>,
+    pub timestamp: u64,
+    // Size and modification time of the renamed file, used to check that
+    // it hasn't been changed before undoing. Not recorded for directories.
+    pub size: Option<u64>,
+    pub modified: Option<u128>,
+    // Whether the file was created in the run instead of renamed, like NFO
+    // files or the directories in a library, which are removed when undoing
+    #[serde(default)]
+    pub created: bool,
+}
+
+// Journal of all the renames done in a single run
+pub struct Journal {
+    pub run_id: String,
+    path: PathBuf,
+    count: AtomicUsize,
+}
+
+impl Journal {
+    // Create a journal for the current run. The file is created right away, so
+    // that another run started in the same second can't take the same id, and
+    // is removed again if nothing gets recorded in it.
+    pub fn new() -> Result<Journal, String> {
+        let dir = journal_dir()?;
+        fs::create_dir_all(&dir).map_err(|e| format!("Unable to create {}: {e}", dir.display()))?;
+        let timestamp = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut run_id = timestamp.clone();
+        let mut suffix = 1;
+        loop {
+            let path = dir.join(format!("{run_id}.jsonl"));
+            // A run that has been undone keeps its id too
+            let taken = dir.join(format!("{run_id}.undone")).exists()
+                || match OpenOptions::new().write(true).create_new(true).open(&path) {
+                    Ok(_) => false,
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => true,
+                    Err(e) => return Err(format!("Unable to create {}: {e}", path.display())),
+                };
+            if !taken {
+                return Ok(Journal {
+                    path,
+                    run_id,
+                    count: AtomicUsize::new(0),
+                });
+            }
+            suffix += 1;
+            run_id = format!("{timestamp}-{suffix}");
+        }
+    }
+
+    // Number of renames recorded so far
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Append a rename to the journal, should be called right after the rename
+    pub fn record(&self, old: &str, new: &str, tmdb_id: Option<u64>) -> io::Result<()> {
+        self.append(old, new, tmdb_id, false)
+    }
+
+    // Append a newly created file to the journal, should be called right after
+    // writing it
+    pub fn record_created(&self, path: &str, tmdb_id: Option<u64>) -> io::Result<()> {
+        self.append("", path, tmdb_id, true)
+    }
+
+    // Move a file that's about to be replaced out of the way, into a directory for
+    // the run next to the journal, so that undoing the run puts it back
+    pub fn backup(&self, path: &Path, tmdb_id: Option<u64>) -> io::Result<()> {
+        let dir = self.path.with_extension("");
+        fs::create_dir_all(&dir)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let backup = dir.join(format!("{}-{name}", self.len()));
+        transfer::move_file(path, &backup, false)?;
+        self.record(
+            &path.display().to_string(),
+            &backup.display().to_string(),
+            tmdb_id,
+        )
+        .inspect_err(|_| {
+            transfer::move_file(&backup, path, false).ok();
+        })
+    }
+
+    fn append(&self, old: &str, new: &str, tmdb_id: Option<u64>, created: bool) -> io::Result<()> {
+        let (size, modified) = file_stamp(new);
+        // Store absolute paths, so that the run can be undone from anywhere
+        let absolute = |p: &str| {
+            path::absolute(p)
+                .map(|p| p.display().to_string())
+                .unwrap_or(p.to_string())
+        };
+        let entry = JournalEntry {
+            old: if created {
+                String::new()
+            } else {
+                absolute(old)
+            },
+            new: absolute(new),
+            tmdb_id,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            size,
+            modified,
+            created,
+        };
+
+        let dir = self.path.parent().unwrap_or(Path::new(""));
+        fs::create_dir_all(dir)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let line = serde_json::to_string(&entry)?;
+        writeln!(file, "{line}")?;
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    // Don't leave an empty journal behind for a run that didn't rename anything
+    fn drop(&mut self) {
+        if self.is_empty() {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+// What happened to an entry of the journal when undoing the run
+pub enum UndoOutcome {
+    // The file was renamed back to its old name
+    Renamed,
+    // The created file or directory was removed
+    Removed,
+    // The created directory was kept, as there's something else in it now
+    NotEmpty,
+    // The file doesn't exist anymore
+    Missing,
+    // The file was changed after the run
+    Modified,
+    // Something else has the old name now
+    Taken,
+    Failed(io::Error),
+}
+
+impl UndoOutcome {
+    // Whether the entry is done with, so that the journal isn't needed anymore
+    pub fn is_done(&self) -> bool {
+        matches!(
+            self,
+            UndoOutcome::Renamed | UndoOutcome::Removed | UndoOutcome::NotEmpty
+        )
+    }
+}
+
+// A run that was undone, along with what happened to every entry, in the order
+// they were undone
+pub struct Undone {
+    pub run_id: String,
+    pub entries: Vec<(JournalEntry, UndoOutcome)>,
+}
+
+impl Undone {
+    // Whether every entry was undone, in which case the run is marked as undone
+    pub fn is_complete(&self) -> bool {
+        self.entries.iter().all(|(_, outcome)| outcome.is_done())
+    }
+}
+
+// Undo all the renames of a run, in reverse order. The latest run is undone if
+// the id is "latest".
+pub fn undo(run_id: &str) -> Result<Undone, String> {
+    let dir = journal_dir()?;
+    let run_id = if run_id == "latest" {
+        latest_run(&dir).ok_or_else(|| String::from("There are no runs to undo."))?
+    } else {
+        run_id.to_string()
+    };
+
+    let path = dir.join(format!("{run_id}.jsonl"));
+    let contents = fs::read_to_string(&path)
+        .map_err(|_| format!("Could not find a journal for the run {run_id}!"))?;
+
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let entry = serde_json::from_str::<JournalEntry>(line).map_err(|e| {
+            format!(
+                "The journal for the run {run_id} is corrupted at line {}: {e}",
+                number + 1
+            )
+        })?;
+        entries.push(entry);
+    }
+
+    let entries: Vec<_> = entries
+        .into_iter()
+        .rev()
+        .map(|entry| {
+            let outcome = undo_entry(&entry);
+            (entry, outcome)
+        })
+        .collect();
+    let undone = Undone { run_id, entries };
+
+    // Mark the run as undone, so that it isn't picked up again. The directory with
+    // the backups is empty by now, if there is one.
+    if undone.is_complete() {
+        fs::rename(&path, dir.join(format!("{}.undone", undone.run_id))).ok();
+        fs::remove_dir(path.with_extension("")).ok();
+    }
+    Ok(undone)
+}
+
+// Undo a single entry of the journal, unless the file was changed since then
+fn undo_entry(entry: &JournalEntry) -> UndoOutcome {
+    let (old, new) = (entry.old.as_str(), entry.new.as_str());
+    if !Path::new(new).exists() {
+        UndoOutcome::Missing
+    } else if entry.created && Path::new(new).is_dir() {
+        // Directories created for the files are only removed once they're empty
+        match fs::remove_dir(new) {
+            Ok(_) => UndoOutcome::Removed,
+            Err(_) => UndoOutcome::NotEmpty,
+        }
+    } else if entry.created {
+        if file_stamp(new) != (entry.size, entry.modified) {
+            return UndoOutcome::Modified;
+        }
+        match fs::remove_file(new) {
+            Ok(_) => UndoOutcome::Removed,
+            Err(e) => UndoOutcome::Failed(e),
+        }
+    } else if Path::new(old).exists() {
+        UndoOutcome::Taken
+    } else if entry.size.is_some() && file_stamp(new) != (entry.size, entry.modified) {
+        UndoOutcome::Modified
+    } else {
+        match transfer::move_file(Path::new(new), Path::new(old), false) {
+            Ok(_) => UndoOutcome::Renamed,
+            Err(e) => UndoOutcome::Failed(e),
+        }
+    }
+}
+
+// Find the name a file had before it was renamed, from the latest run that renamed
+// it and hasn't been undone
+pub fn original_name(path: &str) -> Option<String> {
+    let path = path::absolute(path).ok()?.display().to_string();
+    let dir = journal_dir().ok()?;
+    runs(&dir).iter().rev().find_map(|run_id| {
+        let contents = fs::read_to_string(dir.join(format!("{run_id}.jsonl"))).ok()?;
+        contents
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<JournalEntry>(line).ok())
+            .find(|entry| entry.new == path && !entry.created)
+            .map(|entry| entry.old)
+    })
+}
+
+// Find the id of the last run which hasn't been undone yet
+fn latest_run(dir: &Path) -> Option<String> {
+    runs(dir).pop()
+}
+
+// The ids of the runs which haven't been undone yet, from the oldest to the newest
+fn runs(dir: &Path) -> Vec<String> {
+    let mut runs: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f.ok())
+        .filter_map(|f| {
+            f.file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".jsonl"))
+                .map(String::from)
+        })
+        .collect();
+    runs.sort_by_key(|run_id| run_order(run_id));
+    runs
+}
+
+// Split a run id like 20240101-120000-10 into its timestamp and its number, so that
+// the runs started in the same second are ordered by the number, not as text. The
+// first of those runs has no number.
+fn run_order(run_id: &str) -> (String, u32) {
+    let (timestamp, number) = match run_id.rsplit_once('-') {
+        // The timestamp itself has a dash, so only a third part is the number
+        Some((timestamp, number)) if timestamp.contains('-') => {
+            (timestamp, number.parse().unwrap_or_default())
+        }
+        _ => (run_id, 1),
+    };
+    (timestamp.to_string(), number)
+}
+
+// Size and modification time of a file, if it is one
+fn file_stamp(path: &str) -> (Option<u64>, Option<u128>) {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => (
+            Some(meta.len()),
+            meta.modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos()),
+        ),
+        _ => (None, None),
+    }
+}
+
+// Get the directory where the journals are stored, which has to be an absolute
+// path so that it doesn't depend on where the program is run from
+fn journal_dir() -> Result<PathBuf, String> {
+    let state_home = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".local/state"),
+    };
+    if !state_home.is_absolute() {
+        return Err(String::from(
+            "Could not find a directory for the journal, set XDG_STATE_HOME or HOME to an absolute path.",
+        ));
+    }
+    Ok(state_home.join("movie-rename"))
+}
@@ -0,0 +1,15 @@
+// The library behind movie-rename, so that other tools can look up movies and
+// get the new names for them without going through the command line
+pub mod api;
+pub mod artwork;
+pub mod cache;
+pub mod config;
+pub mod fixture;
+pub mod journal;
+pub mod nfo;
+pub mod provider;
+pub mod resolve;
+pub mod score;
+pub mod structs;
+pub mod template;
+pub mod transfer;
@@ -1,140 +1,373 @@
-use load_file::{self, load_str};
-use std::{collections::HashMap, env, fs, path::Path, process::exit};
-use tmdb_api::client::{reqwest::ReqwestExecutor, Client};
+use movie_rename::{
+    api::{self, RateLimiter, Tmdb},
+    artwork::Artwork,
+    cache::{self, Cache},
+    config::{Config, ConflictPolicy, Mode, ProviderKind},
+    fixture::Fixture,
+    journal::{self, Journal, UndoOutcome, Undone},
+    provider::MetadataProvider,
+    resolve::Resolver,
+    structs::{MovieId, Settings, PATTERN_VARIABLES},
+    template::Template,
+};
+use std::{path::Path, process::exit};
 
 // Import all the modules
 mod functions;
-use functions::process_file;
+use functions::{
+    collect_files, prefetch, print_event, process_directory, process_file, process_tree,
+};
 mod args;
-mod structs;
+mod plan;
 
 #[tokio::main]
 async fn main() {
     // Process the passed arguments
-    let (entries, settings) = args::process_args();
+    let (entries, settings, options) = args::process_args();
     let flag_dry_run = settings["dry-run"];
     let flag_directory = settings["directory"];
     let flag_lucky = settings["i-feel-lucky"];
+    let flag_recursive = settings["recursive"];
+    let flag_offline = settings["offline"];
+    let flag_nfo = settings["nfo"];
+    let flag_artwork = settings["artwork"];
+
+    // Undo a previous run if asked to, and do nothing else
+    if let Some(run_id) = options.get("undo") {
+        match journal::undo(run_id) {
+            Ok(undone) => {
+                print_undone(&undone);
+                if !undone.is_complete() {
+                    exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        }
+        return;
+    }
 
     // Print some message when flags are set.
     if flag_dry_run {
         println!("Doing a dry run. No files will be modified.")
     }
-    if flag_directory {
+    if flag_recursive {
+        println!("Running in recursive mode...")
+    } else if flag_directory {
         println!("Running in directory mode...")
     }
     if flag_lucky {
         println!("Automatically selecting the first entry...")
     }
+    if flag_offline {
+        println!("Running in offline mode, only cached results will be used...")
+    }
 
     // Try to read config file, or display error
-    let mut config_file = env::var("XDG_CONFIG_HOME").unwrap_or(String::from("$HOME"));
-    if config_file == "$HOME" {
-        config_file = env::var("$HOME").unwrap();
-        config_file.push_str("/.config");
+    let config = match Config::load(options.get("profile").map(|p| p.as_str())) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("Pass --help to see help.");
+            exit(2);
+        }
+    };
+
+    // Parse the pattern, so that mistakes are caught before doing anything
+    let pattern = match Template::parse(&config.pattern, &PATTERN_VARIABLES) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("Invalid pattern '{}': {e}", config.pattern);
+            exit(2);
+        }
+    };
+
+    let folder_pattern = match Template::parse(&config.folder_pattern, &PATTERN_VARIABLES) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("Invalid folder pattern '{}': {e}", config.folder_pattern);
+            exit(2);
+        }
+    };
+
+    // The library to move the files into, if any
+    let library_root = options.get("library-root").cloned().or(config.library_root);
+    if let Some(root) = &library_root {
+        if Path::new(root).exists() && !Path::new(root).is_dir() {
+            eprintln!("The library root {root} is not a directory.");
+            exit(2);
+        }
+        println!("Moving the files into the library at {root}...");
     }
-    config_file.push_str("/movie-rename/config");
 
-    if !Path::new(config_file.as_str()).is_file() {
-        eprintln!("Error reading the config file. Pass --help to see help.");
+    // Read how the files should get their new names
+    let mode = options
+        .get("mode")
+        .and_then(|m| Mode::parse(m))
+        .unwrap_or(config.mode);
+    match mode {
+        Mode::Rename | Mode::Move => (),
+        Mode::Copy => println!("Copying the files, the originals will be left as they are..."),
+        _ => println!(
+            "Creating {}s to the files, the originals will be left as they are...",
+            mode.name()
+        ),
+    }
+
+    // Read the confidence threshold for automatic matching
+    let auto_threshold = match options.get("auto-threshold").map(|t| t.parse::<f64>()) {
+        None => None,
+        Some(Ok(threshold)) if (0.0..=1.0).contains(&threshold) => Some(threshold),
+        Some(_) => {
+            eprintln!("The value for --auto-threshold must be a number between 0 and 1.");
+            exit(2);
+        }
+    };
+
+    // Read the number of files to look up at the same time
+    let jobs = match options.get("jobs").map(|j| j.parse::<usize>()) {
+        None => 1,
+        Some(Ok(jobs)) if jobs > 0 => jobs,
+        Some(_) => {
+            eprintln!("The value for --jobs must be a positive number.");
+            exit(2);
+        }
+    };
+
+    // Read the id of the movie to use instead of searching, for a single file
+    let movie_id = match (options.get("tmdb-id"), options.get("imdb-id")) {
+        (Some(id), _) => match MovieId::parse(id) {
+            Some(id @ MovieId::Tmdb(_)) => Some(id),
+            _ => {
+                eprintln!("The value for --tmdb-id must be a TMDB ID, like 25587.");
+                exit(2);
+            }
+        },
+        (_, Some(id)) => match MovieId::parse(id) {
+            Some(id @ MovieId::Imdb(_)) => Some(id),
+            _ => {
+                eprintln!("The value for --imdb-id must be an IMDb ID, like tt0052572.");
+                exit(2);
+            }
+        },
+        (None, None) => None,
+    };
+    if movie_id.is_some() && entries.len() != 1 {
+        eprintln!("Only a single file can be given along with --tmdb-id or --imdb-id.");
         exit(2);
     }
 
-    let mut config = load_str!(config_file.as_str()).lines();
-    let api_key = config.next().unwrap_or("");
-    let pattern = config.next().unwrap_or("{title} ({year}) - {director}");
+    let settings = Settings {
+        pattern,
+        language: config.language,
+        region: config.region.map(|r| r.to_uppercase()),
+        extensions: config.extensions,
+        on_conflict: options
+            .get("on-conflict")
+            .and_then(|p| ConflictPolicy::parse(p))
+            .unwrap_or(config.on_conflict),
+        mode,
+        credits_concurrency: config.credits_concurrency,
+        lazy_credits: config.lazy_credits,
+        dry_run: flag_dry_run,
+        lucky: flag_lucky,
+        recursive: flag_recursive,
+        auto_threshold,
+        movie_id,
+        nfo: flag_nfo,
+        movie_nfo: config.movie_nfo,
+        library_root,
+        folder_pattern,
+    };
 
-    if api_key.is_empty() {
-        eprintln!("Could not read the API key. Pass --help to see help.");
+    if flag_offline && !config.cache.enabled {
+        eprintln!("The cache needs to be enabled for --offline to work.");
         exit(2);
     }
+    if config.provider == ProviderKind::Tmdb && config.cache.enabled && cache::cache_dir().is_none()
+    {
+        eprintln!("Could not find a directory for the cache, set XDG_CACHE_HOME or HOME to an absolute path.");
+        if flag_offline {
+            exit(2);
+        }
+        eprintln!("Continuing without the cache...");
+    }
+
+    // The HTTP client, shared by TMDb and the downloads of the artwork
+    let http_client = match api::http_client(&config.http) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(2);
+        }
+    };
+    let artwork = flag_artwork.then(|| Artwork::new(http_client.clone(), &config.artwork));
+
+    // Create the provider for looking up the movies, TMDb unless asked otherwise
+    let provider: Box<dyn MetadataProvider> = match config.provider {
+        ProviderKind::Tmdb => {
+            let client = match api::client(config.api_key, http_client, &config.http) {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("{e}");
+                    exit(2);
+                }
+            };
+            let cache = Cache::new(&config.cache, flag_offline);
+            Box::new(
+                Tmdb::new(
+                    client,
+                    cache,
+                    RateLimiter::new(config.requests_per_second),
+                    config.max_retries,
+                )
+                .with_events(print_event),
+            )
+        }
+        ProviderKind::Fixture => match Fixture::load(&config.fixture.unwrap_or_default()) {
+            Ok(fixture) => Box::new(fixture),
+            Err(e) => {
+                eprintln!("{e}");
+                exit(2);
+            }
+        },
+    };
+
+    // Resolver for looking up the files, which shows what happens along the way
+    let resolver = Resolver::new(provider.as_ref(), &settings).with_events(print_event);
+
+    // Look up the files in parallel, if asked to. The prompts and renames still
+    // happen one by one below, so that the output stays the same.
+    if jobs > 1 && !options.contains_key("apply") {
+        let filenames = collect_files(&entries, flag_directory, flag_recursive);
+        prefetch(&filenames, provider.as_ref(), &settings, jobs).await;
+    }
 
-    // Create TMDb object for API calls
-    let tmdb = Client::<ReqwestExecutor>::new(String::from(api_key));
+    // Only look up the files and write the plan, if asked to
+    if let Some(plan_file) = options.get("plan") {
+        let filenames = collect_files(&entries, flag_directory, flag_recursive);
+        if !plan::write_plan(filenames, &resolver, &settings, plan_file).await {
+            exit(1);
+        }
+        return;
+    }
+
+    // Journal for keeping track of the renames done in this run
+    let journal = match Journal::new() {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("{e}");
+            exit(2);
+        }
+    };
+
+    // Rename as per a plan that was written earlier, if asked to
+    if let Some(plan_file) = options.get("apply") {
+        let success = plan::apply_plan(plan_file, &settings, &journal);
+        print_journal_info(&journal);
+        // exit() skips the cleanup of an empty journal
+        drop(journal);
+        if !success {
+            exit(1);
+        }
+        return;
+    }
 
     // Iterate over entries
     for entry in entries {
         // Check if the file/directory exists on disk and run necessary commands
-        match flag_directory {
+        match (flag_directory, flag_recursive) {
             // Normal file
-            false => {
+            (false, false) => {
                 if Path::new(entry.as_str()).is_file() {
                     // Process the filename for movie entries
-                    process_file(&entry, &tmdb, pattern, flag_dry_run, flag_lucky, None).await;
+                    process_file(
+                        &entry,
+                        &resolver,
+                        &settings,
+                        &journal,
+                        artwork.as_ref(),
+                        None,
+                    )
+                    .await;
                 } else {
                     eprintln!("The file {entry} wasn't found on disk, skipping...");
                     continue;
                 }
             }
             // Directory
-            true => {
+            (true, false) => {
                 if Path::new(entry.as_str()).is_dir() {
-                    println!("Processing files inside the directory {entry}...");
-                    let mut movie_list = HashMap::new();
-
-                    if let Ok(files_in_dir) = fs::read_dir(entry.as_str()) {
-                        let filename_list: Vec<_> = files_in_dir
-                            .filter(|f| f.is_ok())
-                            .map(|f| f.unwrap().path().display().to_string())
-                            .collect();
-                        for filename in filename_list {
-                            let (filename_without_ext, movie_name_temp, add_to_list) =
-                                process_file(
-                                    &filename,
-                                    &tmdb,
-                                    pattern,
-                                    flag_dry_run,
-                                    flag_lucky,
-                                    Some(&movie_list),
-                                )
-                                .await;
-
-                            if add_to_list {
-                                movie_list.insert(filename_without_ext, movie_name_temp);
-                            }
-                        }
-                    } else {
-                        eprintln!("There was an error accessing the directory {entry}!");
-                        continue;
-                    }
-                    if movie_list.len() == 1 {
-                        let entry_clean = entry.trim_end_matches('/');
-                        let movie_name = movie_list.into_values().next().unwrap();
-
-                        // If the file was ignored, exit
-                        match movie_name {
-                            None => {
-                                eprintln!("Not renaming directory as only movie was skipped.");
-                            }
-
-                            Some(name) => {
-                                if entry_clean == name {
-                                    println!(
-                                        "[directory] '{entry_clean}' already has correct name."
-                                    );
-                                } else {
-                                    println!("[directory] '{entry_clean}' -> '{name}'",);
-                                    if !flag_dry_run {
-                                        if !Path::new(name.as_str()).is_dir() {
-                                            fs::rename(entry, name)
-                                                .expect("Unable to rename directory!");
-                                        } else {
-                                            eprintln!(
-                                                "Destination directory already exists, skipping..."
-                                            );
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        eprintln!("Could not determine how to rename the directory {entry}!");
-                    }
+                    process_directory(
+                        &entry,
+                        &resolver,
+                        &settings,
+                        &journal,
+                        artwork.as_ref(),
+                        true,
+                    )
+                    .await;
                 } else {
                     eprintln!("The directory {entry} wasn't found on disk, skipping...");
                     continue;
                 }
             }
+            // Whole library
+            (_, true) => {
+                if Path::new(entry.as_str()).is_dir() {
+                    process_tree(&entry, &resolver, &settings, &journal, artwork.as_ref()).await;
+                } else {
+                    eprintln!("The directory {entry} wasn't found on disk, skipping...");
+                    continue;
+                }
+            }
+        }
+    }
+
+    print_journal_info(&journal);
+}
+
+// Show what happened to every entry of a run that was undone
+fn print_undone(undone: &Undone) {
+    println!("Undoing the run {}...", undone.run_id);
+    for (entry, outcome) in &undone.entries {
+        let (old, new) = (&entry.old, &entry.new);
+        match outcome {
+            UndoOutcome::Renamed => println!("  [undo] '{new}' -> '{old}'"),
+            UndoOutcome::Removed => println!("  [undo] removing '{new}'"),
+            UndoOutcome::NotEmpty => println!("  '{new}' isn't empty, keeping it..."),
+            UndoOutcome::Missing => eprintln!("  '{new}' doesn't exist anymore, skipping..."),
+            UndoOutcome::Modified if entry.created => {
+                eprintln!("  '{new}' was modified after it was created, skipping...")
+            }
+            UndoOutcome::Modified => {
+                eprintln!("  '{new}' was modified after the rename, skipping...")
+            }
+            UndoOutcome::Taken => eprintln!("  '{old}' already exists, skipping..."),
+            UndoOutcome::Failed(e) if entry.created => {
+                eprintln!("  Unable to remove '{new}': {e}")
+            }
+            UndoOutcome::Failed(e) => eprintln!("  Unable to rename '{new}': {e}"),
         }
     }
+    if !undone.is_complete() {
+        eprintln!(
+            "Some entries couldn't be undone, keeping the journal for {}.",
+            undone.run_id
+        );
+    }
+}
+
+// Tell the user how to undo the renames done in this run
+fn print_journal_info(journal: &Journal) {
+    if !journal.is_empty() {
+        println!(
+            "Recorded {} rename(s) as run {}, pass --undo={} to revert them.",
+            journal.len(),
+            journal.run_id,
+            journal.run_id
+        );
+    }
 }
@@ -0,0 +1,62 @@
+use crate::structs::MovieEntry;
+
+// Create the contents of a Kodi style NFO file for a movie, which Jellyfin and
+// other media servers can read too
+pub fn render(movie: &MovieEntry) -> String {
+    let mut lines = vec![
+        String::from(r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#),
+        String::from("<movie>"),
+    ];
+    let mut push = |tag: &str, value: &str| {
+        lines.push(format!("  <{tag}>{}</{tag}>", escape(value)));
+    };
+
+    push("title", &movie.title);
+    push("originaltitle", &movie.original_title);
+    if let Some(year) = &movie.year {
+        push("year", year);
+    }
+    if let Some(release_date) = &movie.release_date {
+        push("premiered", release_date);
+    }
+    if let Some(overview) = &movie.overview {
+        push("plot", overview);
+    }
+    if let Some(runtime) = movie.runtime {
+        push("runtime", &runtime.to_string());
+    }
+    for genre in &movie.genres {
+        push("genre", genre);
+    }
+    for director in &movie.directors {
+        push("director", director);
+    }
+
+    if let Some(collection) = &movie.collection {
+        lines.push(format!(
+            "  <set>\n    <name>{}</name>\n  </set>",
+            escape(collection)
+        ));
+    }
+    lines.push(format!(
+        r#"  <uniqueid type="tmdb" default="true">{}</uniqueid>"#,
+        movie.id
+    ));
+    if let Some(imdb_id) = &movie.imdb_id {
+        lines.push(format!(
+            r#"  <uniqueid type="imdb">{}</uniqueid>"#,
+            escape(imdb_id)
+        ));
+    }
+    lines.push(String::from("</movie>"));
+    lines.join("\n") + "\n"
+}
+
+// Escape the characters that have a special meaning in XML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,214 @@
+use crate::functions::move_file;
+use movie_rename::{
+    journal::Journal,
+    resolve::{auto_choice, FirstCandidate, ParsedFile, Resolver},
+    structs::{MovieEntry, Settings},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path, path::Path};
+
+// A plan for renaming files, which can be reviewed and edited before applying it
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub files: Vec<PlannedFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub source: String,
+    // Index of the chosen candidate, null to leave the file alone
+    pub choice: Option<usize>,
+    pub candidates: Vec<Candidate>,
+}
+
+// A possible match for a file, along with the name it would get
+#[derive(Serialize, Deserialize)]
+pub struct Candidate {
+    pub tmdb_id: u64,
+    pub title: String,
+    pub year: Option<String>,
+    pub director: Option<String>,
+    pub score: Option<f64>,
+    pub new_name: String,
+}
+
+// Look up all the files and write the candidates to a plan file, without
+// touching any of the files
+pub async fn write_plan(
+    filenames: Vec<String>,
+    resolver: &Resolver<'_>,
+    settings: &Settings,
+    plan_file: &str,
+) -> bool {
+    let mut plan = Plan { files: Vec::new() };
+    // Related files, like subtitles, reuse the search results of the movie
+    let mut searched: HashMap<String, Vec<MovieEntry>> = HashMap::new();
+
+    for filename in filenames {
+        let file_base = filename.rsplit('/').next().unwrap_or_default();
+        let Some(file) = ParsedFile::new(&filename) else {
+            println!("  Could not parse filename for {file_base}...");
+            continue;
+        };
+        if !settings.extensions.contains(&file.extension) {
+            println!("  Ignoring {file_base}...");
+            continue;
+        }
+        println!("  Looking up {file_base}...");
+
+        // Subtitles without a language in their name are assumed to be in English,
+        // the name can be edited in the plan if that's wrong
+        let extension = resolver.extension(&file, &mut FirstCandidate);
+
+        let movie_list = match searched.get(&file.stem) {
+            Some(list) => list.clone(),
+            None => {
+                // Files with the id of the movie in them only have that candidate
+                let list = match resolver.known_movie(&file).await {
+                    Some(movie) => vec![movie],
+                    None => {
+                        // The lazy credits setting is ignored, since all the names are needed
+                        let mut list = resolver.candidates(&file).await;
+                        if settings.lazy_credits {
+                            resolver.fetch_directors(&mut list).await;
+                        }
+                        for movie in list.iter_mut() {
+                            resolver.fetch_details(movie, file_base).await;
+                        }
+                        list
+                    }
+                };
+                searched.insert(file.stem.clone(), list.clone());
+                list
+            }
+        };
+
+        // Only choose automatically if confident enough, when asked to
+        let choice = if settings.auto_threshold.is_some() {
+            auto_choice(&movie_list, settings.auto_threshold)
+        } else {
+            (!movie_list.is_empty()).then_some(0)
+        };
+
+        let candidates: Vec<Candidate> = movie_list
+            .into_iter()
+            .map(|movie| Candidate {
+                new_name: new_name(resolver, settings, &file, &movie, &extension),
+                tmdb_id: movie.id,
+                title: movie.title,
+                year: movie.year,
+                director: movie.director,
+                score: movie.score,
+            })
+            .collect();
+
+        plan.files.push(PlannedFile {
+            source: path::absolute(&filename)
+                .map(|p| p.display().to_string())
+                .unwrap_or(filename),
+            choice,
+            candidates,
+        });
+    }
+
+    let contents = serde_json::to_string_pretty(&plan).expect("Unable to serialize the plan!");
+    match fs::write(plan_file, contents) {
+        Ok(_) => {
+            println!(
+                "Wrote the plan for {} file(s) to {plan_file}.",
+                plan.files.len()
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("Unable to write the plan to {plan_file}: {e}");
+            false
+        }
+    }
+}
+
+// Get the new name for a file in the plan, which is the full path to the file if it's
+// to be moved into a library
+fn new_name(
+    resolver: &Resolver<'_>,
+    settings: &Settings,
+    file: &ParsedFile,
+    movie: &MovieEntry,
+    extension: &str,
+) -> String {
+    let name = format!("{}.{extension}", resolver.base_name(file, movie));
+    match (&settings.library_root, resolver.folder(file, movie)) {
+        (Some(root), Some(folder)) => {
+            let path = Path::new(root).join(folder).join(name);
+            path::absolute(&path).unwrap_or(path).display().to_string()
+        }
+        _ => name,
+    }
+}
+
+// Rename the files as per the choices in a plan file
+pub fn apply_plan(plan_file: &str, settings: &Settings, journal: &Journal) -> bool {
+    let plan: Plan = match fs::read_to_string(plan_file) {
+        Ok(contents) => match serde_json::from_str(&contents) {
+            Ok(plan) => plan,
+            Err(e) => {
+                eprintln!("Error parsing the plan {plan_file}: {e}");
+                return false;
+            }
+        },
+        Err(e) => {
+            eprintln!("Unable to read the plan {plan_file}: {e}");
+            return false;
+        }
+    };
+
+    println!("Applying the plan {plan_file}...");
+    let mut all_done = true;
+    for file in plan.files {
+        let source = Path::new(&file.source);
+        let Some(index) = file.choice else {
+            println!("  Skipping {} as nothing was chosen...", file.source);
+            continue;
+        };
+        let Some(candidate) = file.candidates.get(index) else {
+            eprintln!("  Invalid choice {index} for {}, skipping...", file.source);
+            all_done = false;
+            continue;
+        };
+        if !source.is_file() {
+            eprintln!(
+                "  The file {} wasn't found on disk, skipping...",
+                file.source
+            );
+            all_done = false;
+            continue;
+        }
+
+        let new_name = source.with_file_name(&candidate.new_name);
+        let file_base = source
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if source == new_name {
+            println!("  [file] '{file_base}' already has correct name.");
+            continue;
+        }
+
+        println!("  [file] '{file_base}' -> '{}'", candidate.new_name);
+        if settings.dry_run {
+            continue;
+        }
+        let new_name = new_name.display().to_string();
+        if let Err(e) = move_file(
+            &file.source,
+            &new_name,
+            candidate.tmdb_id,
+            settings,
+            journal,
+        ) {
+            eprintln!("  {e}");
+            all_done = false;
+        }
+    }
+    all_done
+}
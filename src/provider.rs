@@ -0,0 +1,40 @@
+use futures_util::future::BoxFuture;
+
+use crate::api::ApiError;
+use crate::structs::{Details, MovieEntry, Translation};
+
+// Where the movie data comes from, so that sources other than TMDB can be used
+pub trait MetadataProvider: Send + Sync {
+    // Search for movies with the given title, with the titles in the given
+    // language and the release dates for the given region, if possible
+    fn search<'a>(
+        &'a self,
+        query: &'a str,
+        year: Option<u16>,
+        language: Option<String>,
+        region: Option<String>,
+    ) -> BoxFuture<'a, Result<Vec<MovieEntry>, ApiError>>;
+
+    // Get the names of the director(s) of a movie
+    fn directors(&self, id: u64) -> BoxFuture<'_, Result<Vec<String>, ApiError>>;
+
+    // Get the full details of a movie, in the given language if possible
+    fn details(
+        &self,
+        id: u64,
+        language: Option<String>,
+    ) -> BoxFuture<'_, Result<Details, ApiError>>;
+
+    // Get the titles of a movie in all the languages it's been translated to
+    fn translations(&self, id: u64) -> BoxFuture<'_, Result<Vec<Translation>, ApiError>>;
+
+    // Get a movie by its id, along with its full details
+    fn movie(
+        &self,
+        id: u64,
+        language: Option<String>,
+    ) -> BoxFuture<'_, Result<MovieEntry, ApiError>>;
+
+    // Get the id of the movie with the given IMDb id
+    fn find_imdb_id<'a>(&'a self, imdb_id: &'a str) -> BoxFuture<'a, Result<u64, ApiError>>;
+}
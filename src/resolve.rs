@@ -0,0 +1,712 @@
+use futures_util::{stream, StreamExt};
+use regex::Regex;
+use std::{fs, path::Path, slice, sync::LazyLock, time::Duration};
+use torrent_name_parser::Metadata;
+
+use crate::api::ApiError;
+use crate::provider::MetadataProvider;
+use crate::score::{media_duration, score_movies};
+use crate::structs::{MediaInfo, MovieEntry, MovieId, Settings, DETAILS_VARIABLES};
+use crate::template::transliterate;
+
+// Something that happened while resolving a file, so that frontends can show it
+pub enum Event<'a> {
+    // Searching failed, so there are no candidates
+    SearchFailed {
+        file: &'a str,
+        error: &'a ApiError,
+    },
+    // Getting the full details of a movie failed, so some variables may be empty
+    DetailsFailed {
+        file: &'a str,
+        error: &'a ApiError,
+    },
+    // Nothing matched, so the search is being tried again with different text
+    SearchRetry {
+        file: &'a str,
+        query: &'a str,
+    },
+    // An id for the movie was found in the name of the file or its directory,
+    // or in an NFO file next to it
+    IdFound {
+        file: &'a str,
+        id: &'a MovieId,
+    },
+    // The movie with the given id couldn't be found
+    LookupFailed {
+        file: &'a str,
+        id: &'a MovieId,
+        error: &'a ApiError,
+    },
+    // Nothing matches the file
+    NoMatches {
+        file: &'a str,
+    },
+    // The best candidate was chosen without asking, as it scored above the threshold
+    AutoChosen {
+        file: &'a str,
+        movie: &'a MovieEntry,
+    },
+    // The language of a subtitle file was found in its name
+    SubtitleLanguage {
+        file: &'a str,
+        language: &'a str,
+    },
+    // A request to TMDB failed for a reason that's likely to go away, so it's sent
+    // again after the delay. The first retry is attempt 1.
+    Retry {
+        attempt: u32,
+        delay: Duration,
+        error: &'a ApiError,
+    },
+}
+
+// What was chosen for a file
+pub enum Choice {
+    // One of the candidates
+    Movie(Box<MovieEntry>),
+    // The movie with the given id, which may not be one of the candidates
+    Id(MovieId),
+    // None of them, search with this text instead
+    Search(String),
+}
+
+// Decides which movie a file is, so that every frontend can ask in its own way
+pub trait Chooser {
+    // Choose one of the candidates for a file, or None to skip it
+    fn choose(&mut self, file: &str, candidates: Vec<MovieEntry>) -> Option<Choice>;
+
+    // Choose the language code for a subtitle file without one in its name,
+    // or None to leave it out of the new name
+    fn subtitle_language(&mut self, file: &str) -> Option<String>;
+
+    // Get the text to search for when nothing matches a file, or None to skip it
+    fn search_text(&mut self, _file: &str) -> Option<String> {
+        None
+    }
+}
+
+// Chooser that always takes the first candidate, and assumes subtitles are in English
+pub struct FirstCandidate;
+
+impl Chooser for FirstCandidate {
+    fn choose(&mut self, _file: &str, candidates: Vec<MovieEntry>) -> Option<Choice> {
+        candidates
+            .into_iter()
+            .next()
+            .map(|movie| Choice::Movie(Box::new(movie)))
+    }
+
+    fn subtitle_language(&mut self, _file: &str) -> Option<String> {
+        Some(String::from("en"))
+    }
+}
+
+// A file along with what could be parsed from its name
+pub struct ParsedFile {
+    pub filename: String,
+    pub file_base: String,
+    // Filename without the extension and the subtitle language, shared by related files
+    pub stem: String,
+    pub extension: String,
+    pub subtitle_language: Option<String>,
+    pub metadata: Metadata,
+}
+
+impl ParsedFile {
+    // Parse a filename, None if there's nothing to be found in it
+    pub fn new(filename: &str) -> Option<ParsedFile> {
+        let file_base = filename.rsplit('/').next().unwrap_or_default().to_string();
+        let metadata = Metadata::from(file_base.as_str()).ok()?;
+
+        let filename_parts: Vec<&str> = filename.rsplit('.').collect();
+        let subtitle_language = subtitle_language(&filename_parts).map(String::from);
+
+        Some(ParsedFile {
+            filename: filename.to_string(),
+            stem: file_stem(filename),
+            extension: metadata.extension().unwrap_or("").to_string(),
+            file_base,
+            subtitle_language,
+            metadata,
+        })
+    }
+
+    pub fn is_subtitle(&self) -> bool {
+        ["srt", "ssa"].contains(&self.extension.as_str())
+    }
+}
+
+// What a file should be renamed to
+pub struct Proposal {
+    pub movie: MovieEntry,
+    // New filename, including the extension. If the files are moved into a library,
+    // it's the whole path inside the library instead, as per the folder pattern.
+    pub new_name: String,
+}
+
+// Looks up files with the provider and proposes new names for them, without touching
+// the files or talking to the user directly
+pub struct Resolver<'a> {
+    provider: &'a dyn MetadataProvider,
+    settings: &'a Settings,
+    on_event: Box<dyn Fn(Event) + Send + Sync + 'a>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(provider: &'a dyn MetadataProvider, settings: &'a Settings) -> Resolver<'a> {
+        Resolver {
+            provider,
+            settings,
+            on_event: Box::new(|_| {}),
+        }
+    }
+
+    // Get called back whenever something happens while resolving
+    pub fn with_events(mut self, on_event: impl Fn(Event) + Send + Sync + 'a) -> Resolver<'a> {
+        self.on_event = Box::new(on_event);
+        self
+    }
+
+    // Resolve a file all the way to its new name, None if it's to be left alone
+    pub async fn resolve(&self, filename: &str, chooser: &mut impl Chooser) -> Option<Proposal> {
+        let file = ParsedFile::new(filename)?;
+        if !self.settings.extensions.contains(&file.extension) {
+            return None;
+        }
+        let movie = match self.known_movie(&file).await {
+            Some(movie) => movie,
+            None if self.settings.movie_id.is_some() => return None,
+            None => {
+                let candidates = self.candidates(&file).await;
+                self.choose(&file, candidates, chooser).await?
+            }
+        };
+        let mut new_name = self.new_name(&file, &movie, chooser);
+        if let (Some(root), Some(folder)) =
+            (&self.settings.library_root, self.folder(&file, &movie))
+        {
+            new_name = Path::new(root)
+                .join(folder)
+                .join(new_name)
+                .display()
+                .to_string();
+        }
+        Some(Proposal { movie, new_name })
+    }
+
+    // Search for the movies that could match a file, scoring them if they're
+    // to be chosen based on confidence
+    pub async fn candidates(&self, file: &ParsedFile) -> Vec<MovieEntry> {
+        let mut movie_list = self.search(file).await;
+        // With lazy credits, the directors are only fetched for the chosen entry
+        if !self.settings.lazy_credits {
+            self.fetch_directors(&mut movie_list).await;
+        }
+
+        if movie_list.is_empty() {
+            self.emit(Event::NoMatches {
+                file: &file.file_base,
+            });
+        } else if self.settings.auto_threshold.is_some() {
+            // The duration is only useful for the movie files
+            let duration = if file.is_subtitle() {
+                None
+            } else {
+                media_duration(&file.filename)
+            };
+            self.score(&mut movie_list, file, duration).await;
+        }
+        movie_list
+    }
+
+    // Choose one of the candidates, either automatically or through the chooser,
+    // and get everything about it that the pattern needs
+    pub async fn choose(
+        &self,
+        file: &ParsedFile,
+        mut candidates: Vec<MovieEntry>,
+        chooser: &mut impl Chooser,
+    ) -> Option<MovieEntry> {
+        let mut choice = if candidates.is_empty() {
+            // Searching with different text only makes sense if someone's asked
+            if self.settings.lucky {
+                return None;
+            }
+            let candidates = self.search_manually(file, chooser).await;
+            if candidates.is_empty() {
+                return None;
+            }
+            self.ask(file, candidates, chooser).await?
+        } else if self.settings.lucky {
+            // Take first choice if in lucky mode
+            candidates.into_iter().next()?
+        } else if let Some(index) = auto_choice(&candidates, self.settings.auto_threshold) {
+            let movie = candidates.swap_remove(index);
+            self.emit(Event::AutoChosen {
+                file: &file.file_base,
+                movie: &movie,
+            });
+            movie
+        } else {
+            self.ask(file, candidates, chooser).await?
+        };
+
+        // Movies looked up by their id don't have the directors yet
+        if self.settings.lazy_credits || choice.director.is_none() {
+            self.fetch_directors(slice::from_mut(&mut choice)).await;
+        }
+        self.fetch_details(&mut choice, &file.file_base).await;
+        Some(choice)
+    }
+
+    // Get the movie for a file if its id is known, either from the command line
+    // or from the file itself, so that there's no need to search for it
+    pub async fn known_movie(&self, file: &ParsedFile) -> Option<MovieEntry> {
+        let id = match &self.settings.movie_id {
+            Some(id) => id.clone(),
+            None => {
+                let id = embedded_id(&file.filename)?;
+                self.emit(Event::IdFound {
+                    file: &file.file_base,
+                    id: &id,
+                });
+                id
+            }
+        };
+        self.lookup(file, &id).await
+    }
+
+    // Get the movie with the given id for a file, instead of searching for it,
+    // along with everything about it that the pattern needs
+    pub async fn lookup(&self, file: &ParsedFile, id: &MovieId) -> Option<MovieEntry> {
+        let mut movie = self.movie_by_id(file, id).await?;
+        self.fetch_directors(slice::from_mut(&mut movie)).await;
+        self.fetch_details(&mut movie, &file.file_base).await;
+        Some(movie)
+    }
+
+    // Let the chooser pick one of the candidates, until it picks a movie or skips the file
+    async fn ask(
+        &self,
+        file: &ParsedFile,
+        mut candidates: Vec<MovieEntry>,
+        chooser: &mut impl Chooser,
+    ) -> Option<MovieEntry> {
+        loop {
+            match chooser.choose(&file.file_base, candidates.clone())? {
+                Choice::Movie(movie) => return Some(*movie),
+                Choice::Id(id) => {
+                    if let Some(movie) = self.movie_by_id(file, &id).await {
+                        return Some(movie);
+                    }
+                }
+                Choice::Search(query) => {
+                    let results = self.search_text(file, &query).await;
+                    if !results.is_empty() {
+                        candidates = results;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn movie_by_id(&self, file: &ParsedFile, id: &MovieId) -> Option<MovieEntry> {
+        let movie = match id {
+            MovieId::Tmdb(tmdb_id) => {
+                self.provider
+                    .movie(*tmdb_id, self.settings.language.clone())
+                    .await
+            }
+            MovieId::Imdb(imdb_id) => match self.provider.find_imdb_id(imdb_id).await {
+                Ok(tmdb_id) => {
+                    self.provider
+                        .movie(tmdb_id, self.settings.language.clone())
+                        .await
+                }
+                Err(error) => Err(error),
+            },
+        };
+        match movie {
+            Ok(movie) => Some(movie),
+            Err(error) => {
+                self.emit(Event::LookupFailed {
+                    file: &file.file_base,
+                    id,
+                    error: &error,
+                });
+                None
+            }
+        }
+    }
+
+    // Get the new name for a file, once the movie is known
+    pub fn new_name(
+        &self,
+        file: &ParsedFile,
+        movie: &MovieEntry,
+        chooser: &mut impl Chooser,
+    ) -> String {
+        let new_name = self.base_name(file, movie);
+        match self.extension(file, chooser).as_str() {
+            "" => new_name,
+            extension => format!("{new_name}.{extension}"),
+        }
+    }
+
+    // Get the new name for a file without the extension, as per the pattern
+    pub fn base_name(&self, file: &ParsedFile, movie: &MovieEntry) -> String {
+        let media = MediaInfo::from(&file.metadata, &file.file_base);
+        movie.rename_format(&self.settings.pattern, &media)
+    }
+
+    // Get the path of the directory for a movie inside the library, if the files
+    // are to be moved into one
+    pub fn folder(&self, file: &ParsedFile, movie: &MovieEntry) -> Option<String> {
+        self.settings.library_root.as_ref()?;
+        let media = MediaInfo::from(&file.metadata, &file.file_base);
+        let folder = movie.rename_format(&self.settings.folder_pattern, &media);
+        let parts: Vec<&str> = folder
+            .split('/')
+            .map(|part| part.trim_matches(|c| "- ".contains(c)))
+            .filter(|part| !["", ".", ".."].contains(part))
+            .collect();
+        Some(parts.join("/"))
+    }
+
+    // Get the extension for the new name, along with the language for subtitles
+    pub fn extension(&self, file: &ParsedFile, chooser: &mut impl Chooser) -> String {
+        if !file.is_subtitle() {
+            return file.extension.clone();
+        }
+        // Try to detect if there's already language info in the filename, else ask to choose
+        let language = if let Some(language) = &file.subtitle_language {
+            self.emit(Event::SubtitleLanguage {
+                file: &file.file_base,
+                language,
+            });
+            Some(language.clone())
+        } else if self.settings.lucky {
+            Some(String::from("en"))
+        } else if self.settings.movie_id.is_some() || embedded_id(&file.filename).is_some() {
+            // Files with a known movie id are renamed without any prompts
+            None
+        } else {
+            chooser.subtitle_language(&file.file_base)
+        };
+        match language {
+            Some(language) => format!("{language}.{}", file.extension),
+            None => file.extension.clone(),
+        }
+    }
+
+    // Search for the movies matching the parsed title. If nothing matches, the
+    // search is tried again without the year, with the title normalized, and
+    // with the words at the end of the title dropped one by one.
+    async fn search(&self, file: &ParsedFile) -> Vec<MovieEntry> {
+        let title = file.metadata.title();
+        let year = file.metadata.year().map(|y| y as u16);
+
+        for (index, (query, year)) in fallback_queries(title, year).into_iter().enumerate() {
+            if index > 0 {
+                self.emit(Event::SearchRetry {
+                    file: &file.file_base,
+                    query: &query,
+                });
+            }
+            match self.search_query(&query, year).await {
+                Ok(results) if results.is_empty() => continue,
+                Ok(results) => return results,
+                Err(error) => {
+                    self.emit(Event::SearchFailed {
+                        file: &file.file_base,
+                        error: &error,
+                    });
+                    break;
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    // Ask for the text to search for, until something is found or nothing is given
+    async fn search_manually(
+        &self,
+        file: &ParsedFile,
+        chooser: &mut impl Chooser,
+    ) -> Vec<MovieEntry> {
+        while let Some(query) = chooser.search_text(&file.file_base) {
+            let movie_list = self.search_text(file, &query).await;
+            if !movie_list.is_empty() {
+                return movie_list;
+            }
+        }
+        Vec::new()
+    }
+
+    // Search for the given text instead of the parsed title
+    async fn search_text(&self, file: &ParsedFile, query: &str) -> Vec<MovieEntry> {
+        match self.search_query(query, None).await {
+            Ok(mut movie_list) if !movie_list.is_empty() => {
+                if !self.settings.lazy_credits {
+                    self.fetch_directors(&mut movie_list).await;
+                }
+                movie_list
+            }
+            Ok(_) => {
+                self.emit(Event::NoMatches {
+                    file: &file.file_base,
+                });
+                Vec::new()
+            }
+            Err(error) => {
+                self.emit(Event::SearchFailed {
+                    file: &file.file_base,
+                    error: &error,
+                });
+                Vec::new()
+            }
+        }
+    }
+
+    async fn search_query(
+        &self,
+        query: &str,
+        year: Option<u16>,
+    ) -> Result<Vec<MovieEntry>, ApiError> {
+        self.provider
+            .search(
+                query,
+                year,
+                self.settings.language.clone(),
+                self.settings.region.clone(),
+            )
+            .await
+    }
+
+    // Get the directors' names for the movie entries, if needed. The requests are
+    // done concurrently, while keeping the order of the entries.
+    pub async fn fetch_directors(&self, movie_list: &mut [MovieEntry]) {
+        if !self.settings.uses("director") && !self.settings.nfo {
+            return;
+        }
+
+        let replies: Vec<_> = stream::iter(movie_list.iter())
+            .map(|movie| self.provider.directors(movie.id))
+            .buffered(self.settings.credits_concurrency)
+            .collect()
+            .await;
+
+        for (movie, reply) in movie_list.iter_mut().zip(replies) {
+            if let Ok(directors) = reply {
+                if !directors.is_empty() {
+                    movie.directors = directors.clone();
+                    let mut directors_text = directors.join(", ");
+                    if let Some(pos) = directors_text.rfind(',') {
+                        directors_text.replace_range(pos..pos + 2, " and ");
+                    }
+                    movie.director = Some(directors_text);
+                }
+            }
+        }
+    }
+
+    // Get the full details of a movie, and the localized title, if the pattern or
+    // the NFO files need them
+    pub async fn fetch_details(&self, movie: &mut MovieEntry, file_base: &str) {
+        if self.settings.nfo || DETAILS_VARIABLES.iter().any(|v| self.settings.uses(v)) {
+            self.get_details(movie, file_base).await;
+        }
+        if self.settings.uses("localized_title") && movie.localized_title.is_none() {
+            match self.provider.translations(movie.id).await {
+                Ok(translations) => {
+                    movie.add_translations(&translations, self.settings.language.as_deref())
+                }
+                Err(error) => self.emit(Event::DetailsFailed {
+                    file: file_base,
+                    error: &error,
+                }),
+            }
+        }
+    }
+
+    // Get the full details of a movie, unless they're already there
+    async fn get_details(&self, movie: &mut MovieEntry, file_base: &str) {
+        if movie.has_details {
+            return;
+        }
+        match self
+            .provider
+            .details(movie.id, self.settings.language.clone())
+            .await
+        {
+            Ok(details) => movie.add_details(details),
+            Err(error) => self.emit(Event::DetailsFailed {
+                file: file_base,
+                error: &error,
+            }),
+        }
+    }
+
+    // Score the candidates for confidence based matching, the duration of the file
+    // is compared with the runtime of the best few candidates, if known
+    async fn score(&self, movie_list: &mut [MovieEntry], file: &ParsedFile, duration: Option<f64>) {
+        if duration.is_some() {
+            score_movies(movie_list, &file.metadata, None);
+            let mut order: Vec<usize> = (0..movie_list.len()).collect();
+            order.sort_by(|&a, &b| {
+                let score = |i: usize| movie_list[i].score.unwrap_or_default();
+                score(b).total_cmp(&score(a))
+            });
+            for index in order.into_iter().take(5) {
+                self.get_details(&mut movie_list[index], &file.file_base)
+                    .await;
+            }
+        }
+        score_movies(movie_list, &file.metadata, duration);
+    }
+
+    fn emit(&self, event: Event) {
+        (self.on_event)(event);
+    }
+}
+
+// Get the searches to try one after another for a title, until something matches
+pub fn fallback_queries(title: &str, year: Option<u16>) -> Vec<(String, Option<u16>)> {
+    let mut queries = vec![(title.to_string(), year)];
+    if year.is_some() {
+        queries.push((title.to_string(), None));
+    }
+
+    let normalized = normalize_query(title);
+    if !normalized.is_empty() && normalized != title {
+        queries.push((normalized.clone(), None));
+    }
+
+    // Names often have extra words at the end, like the language or the source.
+    // A single word matches far too much, so it's only searched along with the year.
+    let words: Vec<&str> = normalized.split(' ').collect();
+    for count in (1..words.len()).rev() {
+        let query = words[..count].join(" ");
+        if year.is_some() {
+            queries.push((query.clone(), year));
+        }
+        if count > 1 {
+            queries.push((query, None));
+        }
+    }
+
+    let mut unique = Vec::new();
+    for query in queries {
+        if !unique.contains(&query) {
+            unique.push(query);
+        }
+    }
+    unique
+}
+
+// Replace the accented letters and drop the punctuation from a title
+fn normalize_query(title: &str) -> String {
+    title
+        .chars()
+        .filter(|c| !['\'', '’'].contains(c))
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_string(),
+            c if c.is_alphanumeric() => match transliterate(c) {
+                // Letters from other scripts are kept as they are
+                t if t.is_empty() => c.to_string(),
+                t => t,
+            },
+            _ => String::from(" "),
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Ids like tmdbid-25587, {tmdb-25587}, imdbid-tt0052572 or {imdb-tt0052572}
+static NAME_ID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(tmdb|imdb)(?:id)?[-=](tt\d+|\d+)\b").expect("Invalid regex")
+});
+
+// Ids in Kodi style NFO files, like <uniqueid type="tmdb">25587</uniqueid>
+static NFO_ID: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)<uniqueid[^>]*\btype="(tmdb|imdb)"[^>]*>\s*(tt\d+|\d+)\s*</uniqueid>"#)
+        .expect("Invalid regex")
+});
+
+// Find the id of the movie in the name of a file, the name of its directory, or
+// an NFO file next to it (with the same name, or movie.nfo), in that order.
+// TMDB ids are preferred, since they don't need an extra request.
+pub fn embedded_id(filename: &str) -> Option<MovieId> {
+    let path = Path::new(filename);
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let name_id = |path: &Path| {
+        path.file_name()
+            .and_then(|name| find_id(&NAME_ID, &name.to_string_lossy()))
+    };
+    let nfo_id = |path: &Path| {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| find_id(&NFO_ID, &contents))
+    };
+
+    name_id(path)
+        .or_else(|| name_id(parent))
+        .or_else(|| nfo_id(&parent.join(format!("{}.nfo", file_stem(filename)))))
+        .or_else(|| nfo_id(&parent.join("movie.nfo")))
+}
+
+// Get the id matched by the regex, where the kind is the first group and the id
+// is the second
+fn find_id(regex: &Regex, text: &str) -> Option<MovieId> {
+    let ids: Vec<MovieId> = regex
+        .captures_iter(text)
+        .filter_map(|captures| {
+            let id = MovieId::parse(&captures[2])?;
+            match (captures[1].to_lowercase().as_str(), &id) {
+                ("tmdb", MovieId::Tmdb(_)) | ("imdb", MovieId::Imdb(_)) => Some(id),
+                _ => None,
+            }
+        })
+        .collect();
+    ids.iter()
+        .find(|id| matches!(id, MovieId::Tmdb(_)))
+        .or(ids.first())
+        .cloned()
+}
+
+// Get the index of the best scoring candidate, if it's above the threshold
+pub fn auto_choice(movie_list: &[MovieEntry], threshold: Option<f64>) -> Option<usize> {
+    let threshold = threshold?;
+    movie_list
+        .iter()
+        .enumerate()
+        .filter_map(|(index, movie)| movie.score.map(|score| (index, score)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .filter(|(_, score)| *score >= threshold)
+        .map(|(index, _)| index)
+}
+
+// Get the filename without the extension, and without the language for subtitles,
+// so that a movie and its related files end up with the same stem
+pub fn file_stem(filename: &str) -> String {
+    let filename_parts: Vec<&str> = filename.rsplit('.').collect();
+    let stem = if subtitle_language(&filename_parts).is_some() {
+        filename.rsplitn(3, '.').last().unwrap_or(filename)
+    } else {
+        filename.rsplit_once('.').map(|x| x.0).unwrap_or(filename)
+    };
+    stem.to_string()
+}
+
+// Get the language code from a subtitle filename like movie.en.srt, the parts
+// are the filename split at the dots, in reverse
+pub fn subtitle_language<'a>(filename_parts: &[&'a str]) -> Option<&'a str> {
+    if filename_parts.len() >= 3 && filename_parts[1].len() == 2 {
+        Some(filename_parts[1])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,77 @@
+use std::process::Command;
+use torrent_name_parser::Metadata;
+
+use crate::structs::MovieEntry;
+
+// Score the candidates between 0 and 1, based on how well they match the parsed filename
+pub fn score_movies(movie_list: &mut [MovieEntry], metadata: &Metadata, duration: Option<f64>) {
+    let max_popularity = movie_list.iter().map(|m| m.popularity).fold(0.0, f64::max);
+    let title = normalize(metadata.title());
+
+    for movie in movie_list.iter_mut() {
+        let title_score = strsim::normalized_levenshtein(&title, &normalize(&movie.title)).max(
+            strsim::normalized_levenshtein(&title, &normalize(&movie.original_title)),
+        );
+
+        // Without a year in the filename, all the candidates are equally likely
+        let year_score = match (
+            metadata.year(),
+            movie.year.as_ref().and_then(|y| y.parse::<i32>().ok()),
+        ) {
+            (Some(year), Some(movie_year)) => match (year - movie_year).abs() {
+                0 => 1.0,
+                1 => 0.5,
+                _ => 0.0,
+            },
+            (Some(_), None) => 0.0,
+            (None, _) => 0.5,
+        };
+
+        let popularity_score = if max_popularity > 0.0 {
+            movie.popularity / max_popularity
+        } else {
+            0.0
+        };
+
+        // Compare the runtime with the duration of the file, if both are known
+        let runtime_score = match (duration, movie.runtime) {
+            (Some(duration), Some(runtime)) if runtime > 0 => {
+                let runtime = runtime as f64 * 60.0;
+                Some(1.0 - ((duration - runtime).abs() / runtime).min(1.0))
+            }
+            _ => None,
+        };
+
+        movie.score = Some(match runtime_score {
+            Some(runtime_score) => {
+                0.5 * title_score + 0.2 * year_score + 0.1 * popularity_score + 0.2 * runtime_score
+            }
+            None => 0.6 * title_score + 0.25 * year_score + 0.15 * popularity_score,
+        });
+    }
+}
+
+// Get the duration of a media file in seconds using ffprobe, if it's available
+pub fn media_duration(filename: &str) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1", filename])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+// Lowercase the title and drop the punctuation, so that only the words are compared
+fn normalize(title: &str) -> String {
+    title
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,13 +1,154 @@
-use std::fmt;
-use tmdb_api::movie::MovieShort;
+use std::{collections::HashMap, fmt};
+use tmdb_api::movie::{Movie, MovieShort};
+use torrent_name_parser::Metadata;
+
+use crate::config::{ConflictPolicy, Mode};
+use crate::template::Template;
+
+// Variables that can be used in the pattern
+pub const PATTERN_VARIABLES: [&str; 16] = [
+    "title",
+    "localized_title",
+    "year",
+    "director",
+    "tmdb_id",
+    "imdb_id",
+    "original_title",
+    "original_language",
+    "release_date",
+    "collection",
+    "resolution",
+    "codec",
+    "quality",
+    "audio",
+    "group",
+    "edition",
+];
+
+// Variables that need the full movie details to be fetched
+pub const DETAILS_VARIABLES: [&str; 2] = ["imdb_id", "collection"];
+
+// Settings for the current run, put together from the config file and the flags
+pub struct Settings {
+    pub pattern: Template,
+    pub language: Option<String>,
+    pub region: Option<String>,
+    pub extensions: Vec<String>,
+    pub on_conflict: ConflictPolicy,
+    // How the files get their new names
+    pub mode: Mode,
+    pub credits_concurrency: usize,
+    pub lazy_credits: bool,
+    pub dry_run: bool,
+    pub lucky: bool,
+    pub recursive: bool,
+    pub auto_threshold: Option<f64>,
+    // The movie to use instead of searching, given on the command line
+    pub movie_id: Option<MovieId>,
+    // Whether to write NFO files for the renamed movies
+    pub nfo: bool,
+    // Whether the NFO file of a movie that's alone in its directory is named movie.nfo
+    pub movie_nfo: bool,
+    // Directory the files are moved into, along with the pattern for the
+    // directories inside it
+    pub library_root: Option<String>,
+    pub folder_pattern: Template,
+}
+
+impl Settings {
+    // Check whether the new names use a variable, including the folder pattern
+    // when moving into a library
+    pub fn uses(&self, variable: &str) -> bool {
+        self.pattern.uses(variable)
+            || (self.library_root.is_some() && self.folder_pattern.uses(variable))
+    }
+}
+
+// Id of a movie on TMDB or IMDb, for when it's known instead of searched for
+#[derive(Clone, PartialEq)]
+pub enum MovieId {
+    Tmdb(u64),
+    Imdb(String),
+}
+
+impl MovieId {
+    // Parse an id like 25587 for TMDB, or tt0052572 for IMDb
+    pub fn parse(text: &str) -> Option<MovieId> {
+        let text = text.trim();
+        match text.strip_prefix("tt") {
+            Some(digits) if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) => {
+                Some(MovieId::Imdb(text.to_string()))
+            }
+            Some(_) => None,
+            None => text.parse().ok().filter(|id| *id > 0).map(MovieId::Tmdb),
+        }
+    }
+}
+
+impl fmt::Display for MovieId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieId::Tmdb(id) => write!(f, "TMDB ID {id}"),
+            MovieId::Imdb(id) => write!(f, "IMDb ID {id}"),
+        }
+    }
+}
+
+// Full details of a movie, which aren't part of the search results
+#[derive(Default)]
+pub struct Details {
+    pub imdb_id: Option<String>,
+    pub collection: Option<String>,
+    pub runtime: Option<u64>,
+    pub genres: Vec<String>,
+}
+
+impl Details {
+    // Create the details from the TMDB response
+    pub fn from(movie: Movie) -> Details {
+        Details {
+            imdb_id: movie.imdb_id,
+            collection: movie.belongs_to_collection.map(|c| c.name),
+            runtime: movie.runtime,
+            genres: movie.genres.into_iter().map(|g| g.name).collect(),
+        }
+    }
+}
+
+// Title of a movie in some language, the region is empty if it's for all of them
+pub struct Translation {
+    pub language: String,
+    pub region: String,
+    pub title: String,
+}
 
 // Struct for movie entries
+#[derive(Clone)]
 pub struct MovieEntry {
     pub title: String,
+    // Title in the preferred language, or in English if there's none in it
+    pub localized_title: Option<String>,
+    pub original_title: String,
     pub id: u64,
+    pub imdb_id: Option<String>,
     pub director: Option<String>,
+    // Names of the directors, which are joined together in director
+    pub directors: Vec<String>,
     pub year: Option<String>,
+    pub release_date: Option<String>,
     pub language: String,
+    pub collection: Option<String>,
+    pub popularity: f64,
+    pub runtime: Option<u64>,
+    // Synopsis of the movie, in the preferred language
+    pub overview: Option<String>,
+    pub genres: Vec<String>,
+    // Paths of the images on TMDB, like /hash.jpg
+    pub poster_path: Option<String>,
+    pub backdrop_path: Option<String>,
+    // How well the entry matches the filename, between 0 and 1
+    pub score: Option<f64>,
+    pub has_details: bool,
 }
 
 impl MovieEntry {
@@ -15,39 +156,120 @@ impl MovieEntry {
     pub fn from(movie: MovieShort) -> MovieEntry {
         MovieEntry {
             title: movie.inner.title,
+            localized_title: None,
+            original_title: movie.inner.original_title,
             id: movie.inner.id,
+            imdb_id: None,
             director: None,
+            directors: Vec::new(),
             year: movie
                 .inner
                 .release_date
                 .map(|date| date.format("%Y").to_string()),
+            release_date: movie
+                .inner
+                .release_date
+                .map(|date| date.format("%Y-%m-%d").to_string()),
             language: get_long_lang(movie.inner.original_language.as_str()),
+            collection: None,
+            popularity: movie.inner.popularity,
+            runtime: None,
+            overview: Some(movie.inner.overview).filter(|o| !o.is_empty()),
+            genres: Vec::new(),
+            poster_path: movie.inner.poster_path,
+            backdrop_path: movie.inner.backdrop_path,
+            score: None,
+            has_details: false,
         }
     }
 
-    // Generate desired filename from movie entry
-    pub fn rename_format(&self, mut format: String) -> String {
+    // Add the info that's only available in the full movie details
+    pub fn add_details(&mut self, details: Details) {
+        self.imdb_id = details.imdb_id;
+        self.collection = details.collection;
+        self.runtime = details.runtime;
+        self.genres = details.genres;
+        self.has_details = true;
+    }
+
+    // Choose the title in the preferred language, like "de" or "de-AT", falling
+    // back to English if there's no title in that language
+    pub fn add_translations(&mut self, translations: &[Translation], language: Option<&str>) {
+        let language = language.unwrap_or("en-US");
+        let (language, region) = match language.split_once('-') {
+            Some((language, region)) => (language, Some(region)),
+            None => (language, None),
+        };
+        // The title for the region is preferred over the ones for other regions
+        let find = |language: &str, region: Option<&str>| {
+            translations
+                .iter()
+                .filter(|t| t.language == language && !t.title.is_empty())
+                .max_by_key(|t| Some(t.region.as_str()) == region)
+                .map(|t| t.title.clone())
+        };
+        self.localized_title = find(language, region)
+            .or_else(|| find("en", Some("US")))
+            .or_else(|| Some(self.title.clone()));
+    }
+
+    // Generate desired filename from movie entry and the technical info of the file
+    pub fn rename_format(&self, pattern: &Template, media: &MediaInfo) -> String {
+        let mut values = HashMap::new();
+
         // Try to sanitize the title to avoid some characters
         let mut title = self.title.clone();
         title = sanitize(title);
-        title.truncate(159);
-        format = format.replace("{title}", title.as_str());
+        truncate(&mut title, 159);
+        values.insert("title", title);
 
-        format = match &self.year {
-            Some(year) => format.replace("{year}", year.as_str()),
-            None => format.replace("{year}", ""),
-        };
+        let mut localized_title =
+            sanitize(self.localized_title.clone().unwrap_or(self.title.clone()));
+        truncate(&mut localized_title, 159);
+        values.insert("localized_title", localized_title);
+
+        values.insert("year", self.year.clone().unwrap_or_default());
+
+        if let Some(name) = &self.director {
+            // Try to sanitize the director's name to avoid some characters
+            let mut director = name.clone();
+            director = sanitize(director);
+            truncate(&mut director, 63);
+            values.insert("director", director);
+        }
+
+        let mut original_title = sanitize(self.original_title.clone());
+        truncate(&mut original_title, 159);
+        values.insert("original_title", original_title);
+
+        values.insert("tmdb_id", self.id.to_string());
+        values.insert("imdb_id", self.imdb_id.clone().unwrap_or_default());
+        values.insert("original_language", self.language.clone());
+        values.insert(
+            "release_date",
+            self.release_date.clone().unwrap_or_default(),
+        );
+
+        if let Some(name) = &self.collection {
+            let mut collection = sanitize(name.clone());
+            truncate(&mut collection, 159);
+            values.insert("collection", collection);
+        }
 
-        format = match &self.director {
-            Some(name) => {
-                // Try to sanitize the director's name to avoid some characters
-                let mut director = name.clone();
-                director = sanitize(director);
-                director.truncate(63);
-                format.replace("{director}", director.as_str())
+        for (name, value) in [
+            ("resolution", &media.resolution),
+            ("codec", &media.codec),
+            ("quality", &media.quality),
+            ("audio", &media.audio),
+            ("group", &media.group),
+            ("edition", &media.edition),
+        ] {
+            if let Some(value) = value {
+                values.insert(name, sanitize(value.clone()));
             }
-            None => format.replace("{director}", ""),
-        };
+        }
+
+        let mut format = pattern.render(&values);
 
         // Try to clean extra spaces and such
         format = format.trim_matches(|c| "- ".contains(c)).to_string();
@@ -79,11 +301,105 @@ impl fmt::Display for MovieEntry {
         }
 
         buffer.push_str(&format!("TMDB ID: {}", self.id));
+
+        if let Some(score) = self.score {
+            buffer.push_str(&format!(", Match: {:.0}%", score * 100.0));
+        }
         // buffer.push_str(&format!("Synopsis: {}", self.overview));
         write!(f, "{buffer}")
     }
 }
 
+// Technical info about a file, as parsed from its name
+#[derive(Default)]
+pub struct MediaInfo {
+    pub resolution: Option<String>,
+    pub codec: Option<String>,
+    pub quality: Option<String>,
+    pub audio: Option<String>,
+    pub group: Option<String>,
+    pub edition: Option<String>,
+}
+
+impl MediaInfo {
+    // Collect the info from the parsed filename
+    pub fn from(metadata: &Metadata, file_base: &str) -> MediaInfo {
+        MediaInfo {
+            resolution: metadata.resolution().map(String::from),
+            codec: metadata.codec().map(String::from),
+            quality: metadata.quality().map(String::from),
+            audio: metadata.audio().map(String::from),
+            group: metadata.group().map(String::from),
+            edition: get_edition(metadata, file_base),
+        }
+    }
+
+    // Number of lines of the resolution, like 1080 for 1080p
+    pub fn resolution_rank(&self) -> Option<u32> {
+        let resolution = self.resolution.as_ref()?.to_lowercase();
+        match resolution.as_str() {
+            "4k" | "uhd" => Some(2160),
+            _ => resolution.trim_end_matches(['p', 'i']).parse().ok(),
+        }
+    }
+
+    // Rank of the source of the file, from 0 for things like CAM to 6 for remuxes
+    pub fn source_rank(&self) -> Option<u8> {
+        self.quality.as_ref().map(|q| {
+            let q = q.to_lowercase().replace(['-', ' '], "");
+            const SOURCES: [(&str, u8); 8] = [
+                ("remux", 6),
+                ("bluray", 5),
+                ("bdrip", 5),
+                ("brrip", 5),
+                ("webdl", 4),
+                ("web", 3),
+                ("hd", 2),
+                ("dvd", 1),
+            ];
+            SOURCES
+                .iter()
+                .find(|(name, _)| q.contains(name))
+                .map_or(0, |(_, rank)| *rank)
+        })
+    }
+}
+
+// Detect the edition of a movie from its filename
+fn get_edition(metadata: &Metadata, file_base: &str) -> Option<String> {
+    static EDITIONS: [(&str, &str); 9] = [
+        ("directors cut", "Director's Cut"),
+        ("director's cut", "Director's Cut"),
+        ("final cut", "Final Cut"),
+        ("theatrical", "Theatrical"),
+        ("ultimate", "Ultimate"),
+        ("remastered", "Remastered"),
+        ("criterion", "Criterion"),
+        ("imax", "IMAX"),
+        ("special edition", "Special Edition"),
+    ];
+
+    // Normalize the separators, so that both Directors.Cut and Directors_Cut match
+    let name = format!(
+        " {} ",
+        file_base.to_lowercase().replace(['.', '_', '-'], " ")
+    );
+    let edition = EDITIONS
+        .iter()
+        .find(|(tag, _)| name.contains(&format!(" {tag} ")))
+        .map(|(_, edition)| edition.to_string());
+
+    if edition.is_some() {
+        edition
+    } else if metadata.extended() {
+        Some(String::from("Extended"))
+    } else if metadata.unrated() {
+        Some(String::from("Unrated"))
+    } else {
+        None
+    }
+}
+
 pub struct Language {
     pub short: String,
     pub long: String,
@@ -158,6 +474,18 @@ pub fn get_long_lang(short: &str) -> String {
     }
 }
 
+// Truncate a string to at most the given number of bytes, without
+// splitting a character in half
+fn truncate(text: &mut String, max: usize) {
+    if text.len() > max {
+        let mut end = max;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+}
+
 // Sanitize filename so that there are no errors while
 // creating a file/directory
 fn sanitize(input: String) -> String {
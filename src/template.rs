@@ -0,0 +1,362 @@
+use std::{collections::HashMap, fmt};
+
+// Filters that can be applied to a variable, like {title|upper}
+#[derive(Clone, Copy)]
+enum Filter {
+    Upper,
+    Lower,
+    Ascii,
+    First,
+}
+
+impl Filter {
+    fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "upper" => Some(Filter::Upper),
+            "lower" => Some(Filter::Lower),
+            "ascii" => Some(Filter::Ascii),
+            "first" => Some(Filter::First),
+            _ => None,
+        }
+    }
+
+    fn apply(self, value: String) -> String {
+        match self {
+            Filter::Upper => value.to_uppercase(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Ascii => value.chars().map(transliterate).collect(),
+            // Lists are joined like "A, B and C", so take the part before the first separator
+            Filter::First => value
+                .split(", ")
+                .next()
+                .and_then(|v| v.split(" and ").next())
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+// Parts of a parsed pattern
+enum Node {
+    Text(String),
+    Variable {
+        name: String,
+        filters: Vec<Filter>,
+        default: Option<String>,
+    },
+    // Optional section, which vanishes if any variable inside it is empty
+    Optional(Vec<Node>),
+}
+
+// Errors found while parsing a pattern
+#[derive(Debug)]
+pub enum TemplateError {
+    UnknownVariable(String),
+    UnknownFilter(String),
+    EmptyVariable(usize),
+    Unclosed(char, usize),
+    Unexpected(char, usize),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::UnknownVariable(name) => write!(f, "unknown variable {{{name}}}"),
+            TemplateError::UnknownFilter(name) => write!(f, "unknown filter '{name}'"),
+            TemplateError::EmptyVariable(pos) => write!(f, "empty variable at column {pos}"),
+            TemplateError::Unclosed(c, pos) => write!(f, "unclosed '{c}' at column {pos}"),
+            TemplateError::Unexpected(c, pos) => write!(f, "unexpected '{c}' at column {pos}"),
+        }
+    }
+}
+
+// A parsed rename pattern
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    // Parse a pattern, only allowing the given variables
+    pub fn parse(pattern: &str, variables: &[&str]) -> Result<Template, TemplateError> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut pos = 0;
+        let nodes = parse_nodes(&chars, &mut pos, variables, None)?;
+        Ok(Template { nodes })
+    }
+
+    // Check whether the pattern uses a variable
+    pub fn uses(&self, variable: &str) -> bool {
+        nodes_use(&self.nodes, variable)
+    }
+
+    // Fill in the variables, missing ones are treated as empty
+    pub fn render(&self, values: &HashMap<&str, String>) -> String {
+        render_nodes(&self.nodes, values).0
+    }
+}
+
+fn parse_nodes(
+    chars: &[char],
+    pos: &mut usize,
+    variables: &[&str],
+    // Position of the '[' if we're inside an optional section
+    section_start: Option<usize>,
+) -> Result<Vec<Node>, TemplateError> {
+    let mut nodes = Vec::new();
+    let mut text = String::new();
+
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        *pos += 1;
+        match c {
+            '\\' => {
+                // Escaped character, e.g. \[ for a literal bracket
+                if let Some(&next) = chars.get(*pos) {
+                    text.push(next);
+                    *pos += 1;
+                }
+            }
+            '{' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                nodes.push(parse_variable(chars, pos, variables)?);
+            }
+            '[' => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(std::mem::take(&mut text)));
+                }
+                let inner = parse_nodes(chars, pos, variables, Some(*pos))?;
+                nodes.push(Node::Optional(inner));
+            }
+            ']' if section_start.is_some() => {
+                if !text.is_empty() {
+                    nodes.push(Node::Text(text));
+                }
+                return Ok(nodes);
+            }
+            '}' | ']' => return Err(TemplateError::Unexpected(c, *pos)),
+            _ => text.push(c),
+        }
+    }
+
+    if let Some(start) = section_start {
+        return Err(TemplateError::Unclosed('[', start));
+    }
+    if !text.is_empty() {
+        nodes.push(Node::Text(text));
+    }
+    Ok(nodes)
+}
+
+// Parse the inside of {...}, the opening brace is already consumed
+fn parse_variable(
+    chars: &[char],
+    pos: &mut usize,
+    variables: &[&str],
+) -> Result<Node, TemplateError> {
+    let start = *pos;
+    let Some(len) = chars[start..].iter().position(|&c| c == '}') else {
+        return Err(TemplateError::Unclosed('{', start));
+    };
+    let inner: String = chars[start..start + len].iter().collect();
+    *pos = start + len + 1;
+
+    let (spec, default) = match inner.split_once(':') {
+        Some((spec, default)) => (spec, Some(default.to_string())),
+        None => (inner.as_str(), None),
+    };
+    let mut parts = spec.split('|').map(str::trim);
+    let name = parts.next().unwrap_or_default();
+    if name.is_empty() {
+        return Err(TemplateError::EmptyVariable(start));
+    }
+    if !variables.contains(&name) {
+        return Err(TemplateError::UnknownVariable(name.to_string()));
+    }
+
+    let mut filters = Vec::new();
+    for filter in parts {
+        match Filter::from_name(filter) {
+            Some(f) => filters.push(f),
+            None => return Err(TemplateError::UnknownFilter(filter.to_string())),
+        }
+    }
+
+    Ok(Node::Variable {
+        name: name.to_string(),
+        filters,
+        default,
+    })
+}
+
+fn nodes_use(nodes: &[Node], variable: &str) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Text(_) => false,
+        Node::Variable { name, .. } => name == variable,
+        Node::Optional(inner) => nodes_use(inner, variable),
+    })
+}
+
+// The bool tells whether all the variables were non-empty, otherwise the
+// enclosing section is dropped
+fn render_nodes(nodes: &[Node], values: &HashMap<&str, String>) -> (String, bool) {
+    let mut out = String::new();
+    let mut all_present = true;
+
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Variable {
+                name,
+                filters,
+                default,
+            } => {
+                let mut value = values.get(name.as_str()).cloned().unwrap_or_default();
+                for filter in filters {
+                    value = filter.apply(value);
+                }
+                if value.is_empty() {
+                    match default {
+                        Some(default) => value = default.clone(),
+                        None => all_present = false,
+                    }
+                }
+                out.push_str(&value);
+            }
+            Node::Optional(inner) => {
+                let (section, complete) = render_nodes(inner, values);
+                if complete {
+                    out.push_str(&section);
+                }
+            }
+        }
+    }
+
+    (out, all_present)
+}
+
+// Replace a character with its closest ASCII equivalent, dropping it if there's none
+pub(crate) fn transliterate(c: char) -> String {
+    if c.is_ascii() {
+        return c.to_string();
+    }
+    #[rustfmt::skip]
+    static TABLE: [(&str, &str); 38] = [("ÀÁÂÃÄÅĀĂĄ", "A"), ("àáâãäåāăą", "a"), ("ÇĆĈĊČ", "C"), ("çćĉċč", "c"),
+        ("ĎĐÐ", "D"), ("ďđð", "d"), ("ÈÉÊËĒĔĖĘĚ", "E"), ("èéêëēĕėęě", "e"), ("ĜĞĠĢ", "G"), ("ĝğġģ", "g"),
+        ("ĤĦ", "H"), ("ĥħ", "h"), ("ÌÍÎÏĨĪĬĮİ", "I"), ("ìíîïĩīĭįı", "i"), ("Ĵ", "J"), ("ĵ", "j"), ("Ķ", "K"), ("ķ", "k"),
+        ("ŁĹĻĽĿ", "L"), ("łĺļľŀ", "l"), ("ÑŃŅŇ", "N"), ("ñńņň", "n"), ("ÒÓÔÕÖØŌŎŐ", "O"), ("òóôõöøōŏő", "o"),
+        ("ŔŖŘ", "R"), ("ŕŗř", "r"), ("ŚŜŞŠ", "S"), ("śŝşš", "s"), ("ŢŤŦ", "T"), ("ţťŧ", "t"),
+        ("ÙÚÛÜŨŪŬŮŰŲ", "U"), ("ùúûüũūŭůűų", "u"), ("Ŵ", "W"), ("ŵ", "w"), ("ÝŶŸ", "Y"), ("ýŷÿ", "y"),
+        ("ŹŻŽ", "Z"), ("źżž", "z")];
+
+    match c {
+        'Æ' => String::from("AE"),
+        'æ' => String::from("ae"),
+        'Œ' => String::from("OE"),
+        'œ' => String::from("oe"),
+        'Þ' => String::from("Th"),
+        'þ' => String::from("th"),
+        'ß' => String::from("ss"),
+        '‘' | '’' => String::from("'"),
+        '–' | '—' => String::from("-"),
+        // Replacements done by sanitize()
+        '∶' => String::from(" -"),
+        _ => TABLE
+            .iter()
+            .find(|(from, _)| from.contains(c))
+            .map(|(_, to)| to.to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARIABLES: [&str; 3] = ["title", "year", "director"];
+
+    fn render(pattern: &str, values: &[(&'static str, &str)]) -> String {
+        let values = values.iter().map(|(k, v)| (*k, v.to_string())).collect();
+        Template::parse(pattern, &VARIABLES)
+            .unwrap()
+            .render(&values)
+    }
+
+    fn error(pattern: &str) -> String {
+        match Template::parse(pattern, &VARIABLES) {
+            Ok(_) => panic!("'{pattern}' should not parse"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn fills_in_variables() {
+        let values = [("title", "Apur Sansar"), ("year", "1959")];
+        assert_eq!(render("{title} ({year})", &values), "Apur Sansar (1959)");
+        assert_eq!(render("{ title }", &values), "Apur Sansar");
+    }
+
+    #[test]
+    fn drops_optional_sections_with_empty_variables() {
+        let pattern = "{title}[ ({year})][ - {director}]";
+        assert_eq!(
+            render(pattern, &[("title", "Apur Sansar"), ("year", "1959")]),
+            "Apur Sansar (1959)"
+        );
+        assert_eq!(render(pattern, &[("title", "Apur Sansar")]), "Apur Sansar");
+        // Nested sections only drop themselves
+        assert_eq!(
+            render(
+                "{title}[ ({year}[, {director}])]",
+                &[("title", "A"), ("year", "1959")]
+            ),
+            "A (1959)"
+        );
+    }
+
+    #[test]
+    fn applies_filters() {
+        let values = [("title", "Amélie"), ("director", "A, B and C")];
+        assert_eq!(render("{title|upper}", &values), "AMÉLIE");
+        assert_eq!(render("{title|lower}", &values), "amélie");
+        assert_eq!(render("{title|ascii|upper}", &values), "AMELIE");
+        assert_eq!(render("{director|first}", &values), "A");
+        assert_eq!(render("{director|first}", &[("director", "A and B")]), "A");
+    }
+
+    #[test]
+    fn uses_defaults_for_empty_variables() {
+        assert_eq!(render("{year:unknown}", &[]), "unknown");
+        assert_eq!(render("{year:unknown}", &[("year", "1959")]), "1959");
+        // Variables with a default don't drop their section
+        assert_eq!(render("[({year:?})]", &[]), "(?)");
+    }
+
+    #[test]
+    fn keeps_escaped_characters() {
+        let values = [("title", "Apur Sansar")];
+        assert_eq!(render(r"\[{title}\]", &values), "[Apur Sansar]");
+        assert_eq!(render(r"\{title\}", &values), "{title}");
+        assert_eq!(render(r"a\\b", &values), r"a\b");
+    }
+
+    #[test]
+    fn reports_errors_with_their_positions() {
+        assert_eq!(error("{name}"), "unknown variable {name}");
+        assert_eq!(error("{title|shout}"), "unknown filter 'shout'");
+        assert_eq!(error("ab{}"), "empty variable at column 3");
+        assert_eq!(error("{title"), "unclosed '{' at column 1");
+        assert_eq!(error("{title}[ {year}"), "unclosed '[' at column 8");
+        assert_eq!(error("{title}]"), "unexpected ']' at column 8");
+        assert_eq!(error("title}"), "unexpected '}' at column 6");
+    }
+
+    #[test]
+    fn knows_which_variables_are_used() {
+        let template = Template::parse("{title}[ {director}]", &VARIABLES).unwrap();
+        assert!(template.uses("title"));
+        assert!(template.uses("director"));
+        assert!(!template.uses("year"));
+    }
+}
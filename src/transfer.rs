@@ -0,0 +1,237 @@
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{self, Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::config::Mode;
+
+// Give a file its new name as per the mode. Unless the destination is to be replaced,
+// this fails with AlreadyExists if there's something there, without touching it.
+// Returns the mode that was actually used, which is Copy if hardlinks or reflinks
+// aren't possible for the file.
+pub fn transfer(from: &Path, to: &Path, mode: Mode, replace: bool) -> io::Result<Mode> {
+    // Replacing a file with itself, like when the destination is a link to it,
+    // would lose the file
+    if replace && same_file(from, to) {
+        return Err(io::Error::other(
+            "the destination is the same file as the source",
+        ));
+    }
+    match mode {
+        Mode::Rename => rename(from, to, replace).map(|_| mode),
+        Mode::Move => move_file(from, to, replace).map(|_| mode),
+        Mode::Copy => copy_file(from, to, replace).map(|_| mode),
+        Mode::Hardlink => match place(to, replace, |temp| fs::hard_link(from, temp)) {
+            // Hardlinks can't cross filesystems
+            Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                copy_file(from, to, replace).map(|_| Mode::Copy)
+            }
+            result => result.map(|_| mode),
+        },
+        Mode::Symlink => {
+            let target = path::absolute(from)?;
+            place(to, replace, |temp| symlink(&target, temp)).map(|_| mode)
+        }
+        Mode::Reflink => match place(to, replace, |temp| reflink(from, temp)) {
+            Err(e) if reflink_unsupported(&e) => copy_file(from, to, replace).map(|_| Mode::Copy),
+            result => result.map(|_| mode),
+        },
+    }
+}
+
+// Create a new file at a temporary path next to the destination, and then rename it
+// to the destination. An existing destination is never written to, it's either
+// replaced as a whole or left alone.
+fn place(to: &Path, replace: bool, create: impl FnOnce(&Path) -> io::Result<()>) -> io::Result<()> {
+    let temp = temp_path(to);
+    create(&temp)?;
+    rename(&temp, to, replace).inspect_err(|_| {
+        fs::remove_file(&temp).ok();
+    })
+}
+
+// Check if two paths lead to the same file, following symlinks
+#[cfg(unix)]
+fn same_file(path: &Path, other: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::metadata(path), fs::metadata(other)) {
+        (Ok(meta), Ok(other_meta)) => {
+            meta.dev() == other_meta.dev() && meta.ino() == other_meta.ino()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(path: &Path, other: &Path) -> bool {
+    match (fs::canonicalize(path), fs::canonicalize(other)) {
+        (Ok(path), Ok(other)) => path == other,
+        _ => false,
+    }
+}
+
+// A hidden path in the same directory as the file, which is unique for the run
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let count = COUNT.fetch_add(1, Ordering::SeqCst);
+    path.with_file_name(format!(".{name}.{}-{count}.part", process::id()))
+}
+
+// Rename a file or a directory. If the destination isn't to be replaced, checking
+// for it is part of the rename where the system allows it, so that another run
+// can't create it in the meantime.
+pub fn rename(from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+    if replace {
+        fs::rename(from, to)
+    } else {
+        rename_no_replace(from, to)
+    }
+}
+
+// Move a file, which also works across filesystems, where it's copied over and
+// the original is only deleted once the copy is known to be complete. Directories
+// can only be renamed within a filesystem.
+pub fn move_file(from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+    match rename(from, to, replace) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if fs::symlink_metadata(from)?.is_dir() {
+                return Err(io::Error::other(
+                    "directories can't be moved to another filesystem",
+                ));
+            }
+            copy_file(from, to, replace)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from_c = CString::new(from.as_os_str().as_bytes())?;
+    let to_c = CString::new(to.as_os_str().as_bytes())?;
+    // SAFETY: both the paths are valid C strings for as long as the call lasts
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_c.as_ptr(),
+            libc::AT_FDCWD,
+            to_c.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        // Some filesystems, like the network ones, can't do it
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => rename_checked(from, to),
+        _ => Err(error),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    rename_checked(from, to)
+}
+
+// Check for the destination right before renaming, where it can't be done atomically
+fn rename_checked(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    fs::rename(from, to)
+}
+
+// Copy a file, making sure that the copy is on the disk and is as large as the
+// original before it gets its name. The modification time is kept.
+fn copy_file(from: &Path, to: &Path, replace: bool) -> io::Result<()> {
+    place(to, replace, |temp| {
+        let original = fs::metadata(from)?;
+        let mut source = fs::File::open(from)?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+
+        // A partial copy is removed
+        let mut verify = || -> io::Result<()> {
+            let copied = io::copy(&mut source, &mut file)?;
+            file.set_permissions(original.permissions())?;
+            if let Ok(modified) = original.modified() {
+                file.set_modified(modified)?;
+            }
+            file.sync_all()?;
+            if copied == original.len() && file.metadata()?.len() == original.len() {
+                Ok(())
+            } else {
+                Err(io::Error::other(
+                    "the copy is not the same size as the original",
+                ))
+            }
+        };
+        verify().inspect_err(|_| {
+            fs::remove_file(temp).ok();
+        })
+    })
+}
+
+#[cfg(unix)]
+fn symlink(from: &Path, to: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(from, to)
+}
+
+#[cfg(not(unix))]
+fn symlink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Clone a file with the FICLONE ioctl, which works on Btrfs, XFS and the like
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs::File::open(from)?;
+    let dest = OpenOptions::new().write(true).create_new(true).open(to)?;
+    // SAFETY: both the file descriptors are open for as long as the call lasts
+    let result = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    let result = if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        match source.metadata().and_then(|m| m.modified()) {
+            Ok(modified) => dest.set_modified(modified),
+            Err(_) => Ok(()),
+        }
+    };
+    if result.is_err() {
+        drop(dest);
+        fs::remove_file(to).ok();
+    }
+    result
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Check if a reflink failed because the filesystem can't do it, rather than
+// something being wrong with the files
+fn reflink_unsupported(error: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    if error.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        io::ErrorKind::CrossesDevices | io::ErrorKind::Unsupported | io::ErrorKind::InvalidInput
+    )
+}
@@ -0,0 +1,1129 @@
+mod common;
+
+use common::{movies, MockTmdb, Movie, TestEnv};
+use std::fs;
+
+fn stdout(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &std::process::Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn renames_a_file() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("file", &server, "");
+    env.touch(&["Apur.Sansar.1959.1080p.BluRay.x264.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.1080p.BluRay.x264.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn joins_multiple_directors() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("directors", &server, "");
+    env.touch(&["Knockin.on.Heavens.Door.1997.mkv"]);
+
+    env.run(&["-l", "Knockin.on.Heavens.Door.1997.mkv"]);
+    assert_eq!(
+        env.files(),
+        ["Knockin' on Heaven's Door (1997) - Thomas Jahn and Til Schweiger.mkv"]
+    );
+}
+
+#[test]
+fn sanitizes_the_new_name() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("sanitize", &server, "");
+    env.touch(&["Mission.Impossible.1996.720p.mkv"]);
+
+    env.run(&["-l", "Mission.Impossible.1996.720p.mkv"]);
+    assert_eq!(
+        env.files(),
+        ["Mission∶ Impossible (1996) - Brian De Palma.mkv"]
+    );
+}
+
+#[test]
+fn keeps_the_subtitle_language() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("subtitles", &server, "");
+    env.touch(&["Apur.Sansar.1959.bn.srt", "Mission.Impossible.1996.srt"]);
+
+    env.run(&[
+        "-l",
+        "Apur.Sansar.1959.bn.srt",
+        "Mission.Impossible.1996.srt",
+    ]);
+    // Without a language in the name, English is assumed in lucky mode
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.bn.srt",
+            "Mission∶ Impossible (1996) - Brian De Palma.en.srt",
+        ]
+    );
+}
+
+#[test]
+fn uses_the_pattern_and_the_details() {
+    let server = MockTmdb::start(movies());
+    let pattern =
+        r#"pattern = '{title} ({year})[ - {collection}] \[imdbid-{imdb_id}\][ {resolution}]'"#;
+    let env = TestEnv::new("pattern", &server, pattern);
+    env.touch(&[
+        "Apur.Sansar.1959.1080p.mkv",
+        "Knockin.on.Heavens.Door.1997.mkv",
+    ]);
+
+    env.run(&[
+        "-l",
+        "Apur.Sansar.1959.1080p.mkv",
+        "Knockin.on.Heavens.Door.1997.mkv",
+    ]);
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - The Apu Trilogy [imdbid-tt0052572] 1080p.mkv",
+            "Knockin' on Heaven's Door (1997) [imdbid-tt0119472].mkv",
+        ]
+    );
+}
+
+#[test]
+fn renames_a_directory_with_related_files() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("directory", &server, "");
+    env.touch(&[
+        "Apur.Sansar.1959.1080p/Apur.Sansar.1959.1080p.mkv",
+        "Apur.Sansar.1959.1080p/Apur.Sansar.1959.1080p.en.srt",
+        "Apur.Sansar.1959.1080p/Apur.Sansar.1959.1080p.nfo",
+    ]);
+
+    let output = env.run(&["-ld", "Apur.Sansar.1959.1080p"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray/Apur Sansar (1959) - Satyajit Ray.en.srt",
+            "Apur Sansar (1959) - Satyajit Ray/Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Apur Sansar (1959) - Satyajit Ray/Apur Sansar (1959) - Satyajit Ray.nfo",
+        ]
+    );
+}
+
+#[test]
+fn renames_a_whole_library() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("recursive", &server, "");
+    env.touch(&[
+        "library/Apur.Sansar.1959/Apur.Sansar.1959.mkv",
+        "library/Bengali/Misc/notes.txt",
+        "library/MI/Mission.Impossible.1996.mkv",
+    ]);
+
+    env.run(&["-lr", "library"]);
+    assert_eq!(
+        env.files(),
+        [
+            "library/Apur Sansar (1959) - Satyajit Ray/Apur Sansar (1959) - Satyajit Ray.mkv",
+            "library/Bengali/Misc/notes.txt",
+            "library/Mission∶ Impossible (1996) - Brian De Palma/Mission∶ Impossible (1996) - Brian De Palma.mkv",
+        ]
+    );
+}
+
+#[test]
+fn dry_run_leaves_the_files_alone() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("dry-run", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-ln", "Apur.Sansar.1959.mkv"]);
+    assert!(stdout(&output).contains("-> 'Apur Sansar (1959) - Satyajit Ray.mkv'"));
+    assert_eq!(env.files(), ["Apur.Sansar.1959.mkv"]);
+    // The journal reserved for the run is removed, as nothing was renamed
+    let journals = fs::read_dir(env.root.join("state/movie-rename")).unwrap();
+    assert_eq!(journals.count(), 0);
+}
+
+#[test]
+fn reads_the_legacy_config_file() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("legacy-config", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+    // The API key on the first line, and the pattern on the second one
+    fs::remove_file(env.root.join("config/movie-rename/config.toml")).unwrap();
+    env.write("config/movie-rename/config", "test\n{title} - {year}\n");
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), ["Apur Sansar - 1959.mkv"]);
+}
+
+#[test]
+fn keeps_the_brackets_of_legacy_patterns() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("legacy-brackets", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+    fs::remove_file(env.root.join("config/movie-rename/config.toml")).unwrap();
+    env.write("config/movie-rename/config", "test\n{title} [{year}]\n");
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), ["Apur Sansar [1959].mkv"]);
+}
+
+#[test]
+fn uses_the_settings_from_the_profile() {
+    let server = MockTmdb::start(movies());
+    let config = "pattern = \"{title}\"\n[profiles.plex]\npattern = \"{title} ({year})\"";
+    let env = TestEnv::new("profile", &server, config);
+    env.touch(&["Apur.Sansar.1959.mkv", "Apur.Sansar.1959.srt"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = env.run(&["-l", "--profile", "plex", "Apur.Sansar.1959.srt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        ["Apur Sansar (1959).en.srt", "Apur Sansar.mkv"]
+    );
+
+    let output = env.run(&["-l", "-p", "kodi", "Apur Sansar.mkv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("The profile 'kodi' is not defined in the config file."));
+}
+
+#[test]
+fn shows_where_the_config_file_is_broken() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("broken-config", &server, "pattern = ");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("config.toml"));
+    assert!(stderr(&output).contains("line 2, column 11"));
+    assert_eq!(env.files(), ["Apur.Sansar.1959.mkv"]);
+}
+
+#[test]
+fn undoes_the_last_run() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("undo", &server, "");
+    env.touch(&[
+        "Apur.Sansar.1959/Apur.Sansar.1959.mkv",
+        "Apur.Sansar.1959/Apur.Sansar.1959.en.srt",
+    ]);
+
+    env.run(&["-ld", "Apur.Sansar.1959"]);
+    assert_ne!(
+        env.files(),
+        [
+            "Apur.Sansar.1959/Apur.Sansar.1959.en.srt",
+            "Apur.Sansar.1959/Apur.Sansar.1959.mkv",
+        ]
+    );
+
+    let output = env.run(&["--undo"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur.Sansar.1959/Apur.Sansar.1959.en.srt",
+            "Apur.Sansar.1959/Apur.Sansar.1959.mkv",
+        ]
+    );
+}
+
+#[test]
+fn skips_files_without_matches() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("no-match", &server, "");
+    env.touch(&["Some.Unknown.Movie.2010.mkv"]);
+
+    let output = env.run(&["-l", "Some.Unknown.Movie.2010.mkv"]);
+    assert!(stderr(&output).contains("Could not find any entries matching"));
+    assert_eq!(env.files(), ["Some.Unknown.Movie.2010.mkv"]);
+}
+
+#[test]
+fn falls_back_to_shorter_searches() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("fallback", &server, "");
+    env.touch(&["Apur.Sansar.Bengali.1960.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.Bengali.1960.mkv"]);
+    assert!(stdout(&output).contains("searching for 'Apur Sansar' instead"));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn looks_up_files_in_parallel() {
+    let server = MockTmdb::start(movies());
+    let names = [
+        "Apur.Sansar.1959.mkv",
+        "Apur.Sansar.Bengali.1960.en.srt",
+        "Knockin.on.Heavens.Door.1997.mkv",
+        "Mission.Impossible.1996.mkv",
+    ];
+    let sequential = TestEnv::new("sequential", &server, "");
+    sequential.touch(&names);
+    let output = sequential.run(&[&["-l"], &names[..]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    let searches = server.requests().len();
+
+    let parallel = TestEnv::new("parallel", &server, "");
+    parallel.touch(&names);
+    let output = parallel.run(&[&["-l", "--jobs", "4"], &names[..]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("with 4 job(s)"));
+    assert_eq!(parallel.files(), sequential.files());
+    // Looking the files up ahead of time doesn't repeat any of the requests
+    assert_eq!(server.requests().len(), 2 * searches);
+}
+
+#[test]
+fn looks_up_everything_the_pattern_needs_ahead_of_time() {
+    let server = MockTmdb::start(movies());
+    let config = "pattern = \"{title} ({year}) [{imdb_id}]\"";
+    let names = ["Apur.Sansar.1959.mkv", "Mission.Impossible.1996.mkv"];
+    let sequential = TestEnv::new("prefetch-sequential", &server, config);
+    sequential.touch(&names);
+    let output = sequential.run(&[&["-a", "0.5"], &names[..]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    let requests = server.requests().len();
+
+    let parallel = TestEnv::new("prefetch-parallel", &server, config);
+    parallel.touch(&names);
+    let output = parallel.run(&[&["-a", "0.5", "-j", "2"], &names[..]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(parallel.files(), sequential.files());
+    assert_eq!(server.requests().len(), 2 * requests);
+
+    // The movie with the given id isn't searched for
+    parallel.touch(&["apu.mkv"]);
+    let output = parallel.run(&["-j", "2", "--tmdb-id", "25587", "apu.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!server.requests().iter().any(|r| r.contains("query=apu")));
+}
+
+#[test]
+fn undoes_the_newest_of_the_runs_in_the_same_second() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("undo-order", &server, "");
+    env.touch(&["nine.mkv", "ten.mkv"]);
+    let journals = env.root.join("state/movie-rename");
+    fs::create_dir_all(&journals).unwrap();
+    for (run, name) in [("9", "nine"), ("10", "ten")] {
+        let entry = serde_json::json!({
+            "old": env.path(&format!("{name}-old.mkv")),
+            "new": env.path(&format!("{name}.mkv")),
+            "tmdb_id": null,
+            "timestamp": 0,
+            "size": null,
+            "modified": null,
+        });
+        fs::write(
+            journals.join(format!("20240101-120000-{run}.jsonl")),
+            entry.to_string(),
+        )
+        .unwrap();
+    }
+
+    let output = env.run(&["--undo"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Undoing the run 20240101-120000-10"));
+    assert_eq!(env.files(), ["nine.mkv", "ten-old.mkv"]);
+
+    // The run id has to be given with =, so that a file isn't taken as one
+    let output = env.run(&["-u", "nine.mkv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(env.files(), ["nine.mkv", "ten-old.mkv"]);
+
+    let output = env.run(&["--undo=20240101-120000-9"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), ["nine-old.mkv", "ten-old.mkv"]);
+}
+
+#[test]
+fn uses_the_given_tmdb_id() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("tmdb-id", &server, "");
+    env.touch(&["apu-3.mkv"]);
+
+    let output = env.run(&["--tmdb-id", "25587", "apu-3.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+    assert!(!server.requests().iter().any(|r| r.contains("/search/")));
+}
+
+#[test]
+fn renames_subtitles_with_the_given_id_without_asking() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("tmdb-id-subtitles", &server, "");
+    env.touch(&["apu-3.srt"]);
+
+    let output = env.run(&["--tmdb-id", "25587", "apu-3.srt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stdout(&output).contains("Could not ask for the language"));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.srt"]);
+}
+
+#[test]
+fn uses_the_given_imdb_id() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("imdb-id", &server, "");
+    env.touch(&["mi.mkv"]);
+
+    let output = env.run(&["--imdb-id", "tt0117060", "mi.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        ["Mission∶ Impossible (1996) - Brian De Palma.mkv"]
+    );
+}
+
+#[test]
+fn reports_unknown_ids() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("unknown-id", &server, "");
+    env.touch(&["mi.mkv", "apu.mkv"]);
+
+    let output = env.run(&["--imdb-id", "tt0000001", "mi.mkv"]);
+    assert!(stderr(&output).contains("Could not get the movie with IMDb ID tt0000001"));
+
+    let output = env.run(&["--tmdb-id", "954", "mi.mkv", "apu.mkv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Only a single file"));
+    assert_eq!(env.files(), ["apu.mkv", "mi.mkv"]);
+}
+
+#[test]
+fn uses_the_id_in_the_filename() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("name-id", &server, "");
+    env.touch(&["MI [tmdbid-954].mkv"]);
+
+    let output = env.run(&["MI [tmdbid-954].mkv"]);
+    assert!(stdout(&output).contains("Found TMDB ID 954"));
+    assert_eq!(
+        env.files(),
+        ["Mission∶ Impossible (1996) - Brian De Palma.mkv"]
+    );
+    assert!(!server.requests().iter().any(|r| r.contains("/search/")));
+}
+
+#[test]
+fn renames_subtitles_with_an_id_without_asking() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("name-id-subtitles", &server, "");
+    env.touch(&["Apu [tmdbid-25587].srt"]);
+
+    let output = env.run(&["Apu [tmdbid-25587].srt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!stdout(&output).contains("Could not ask for the language"));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.srt"]);
+}
+
+#[test]
+fn uses_the_id_in_the_directory_name() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("dir-id", &server, "");
+    env.touch(&["Apu {imdb-tt0052572}/video.mkv"]);
+
+    env.run(&["-d", "Apu {imdb-tt0052572}"]);
+    assert_eq!(
+        env.files(),
+        ["Apur Sansar (1959) - Satyajit Ray/Apur Sansar (1959) - Satyajit Ray.mkv"]
+    );
+}
+
+#[test]
+fn uses_the_id_in_the_nfo_file() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("nfo-id", &server, "");
+    env.touch(&["Knockin.mkv"]);
+    env.write(
+        "files/Knockin.nfo",
+        r#"<movie><uniqueid type="imdb">tt0119472</uniqueid><uniqueid type="tmdb" default="true">5072</uniqueid></movie>"#,
+    );
+
+    let output = env.run(&["Knockin.mkv"]);
+    assert!(stdout(&output).contains("Found TMDB ID 5072"));
+    assert_eq!(
+        env.files(),
+        [
+            "Knockin' on Heaven's Door (1997) - Thomas Jahn and Til Schweiger.mkv",
+            "Knockin.nfo"
+        ]
+    );
+}
+
+#[test]
+fn searches_when_the_embedded_id_is_unknown() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("bad-id", &server, "");
+    env.touch(&["Apur.Sansar.1959.tmdbid-1.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.tmdbid-1.mkv"]);
+    assert!(stderr(&output).contains("Could not get the movie with TMDB ID 1"));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn writes_nfo_files() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("nfo", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv", "Apur.Sansar.1959.en.srt"]);
+
+    let output = env.run(&[
+        "-l",
+        "--nfo",
+        "Apur.Sansar.1959.mkv",
+        "Apur.Sansar.1959.en.srt",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.en.srt",
+            "Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Apur Sansar (1959) - Satyajit Ray.nfo"
+        ]
+    );
+
+    let nfo = std::fs::read_to_string(env.root.join("files/Apur Sansar (1959) - Satyajit Ray.nfo"))
+        .unwrap();
+    for line in [
+        "<title>Apur Sansar</title>",
+        "<originaltitle>অপুর সংসার</originaltitle>",
+        "<year>1959</year>",
+        "<plot>Apu, an unemployed graduate, unexpectedly ends up married &amp; moves to Calcutta with his bride.</plot>",
+        "<runtime>117</runtime>",
+        "<genre>Drama</genre>",
+        "<director>Satyajit Ray</director>",
+        r#"<uniqueid type="tmdb" default="true">25587</uniqueid>"#,
+        r#"<uniqueid type="imdb">tt0052572</uniqueid>"#,
+    ] {
+        assert!(nfo.contains(line), "{line} is missing from {nfo}");
+    }
+
+    // The NFO file is removed when undoing
+    env.run(&["--undo"]);
+    assert_eq!(
+        env.files(),
+        ["Apur.Sansar.1959.en.srt", "Apur.Sansar.1959.mkv"]
+    );
+}
+
+#[test]
+fn brings_back_the_replaced_nfo_file_when_undoing() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("nfo-overwrite", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+    env.write("files/Apur Sansar (1959) - Satyajit Ray.nfo", "mine");
+    let nfo = env.path("Apur Sansar (1959) - Satyajit Ray.nfo");
+
+    // Without overwriting, the existing NFO file is left alone
+    let output = env.run(&["-l", "--nfo", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(&nfo).unwrap(), "mine");
+    env.run(&["--undo"]);
+
+    let output = env.run(&[
+        "-l",
+        "--nfo",
+        "--on-conflict",
+        "overwrite",
+        "Apur.Sansar.1959.mkv",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(fs::read_to_string(&nfo)
+        .unwrap()
+        .contains("<title>Apur Sansar</title>"));
+
+    let output = env.run(&["--undo"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(&nfo).unwrap(), "mine");
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.nfo",
+            "Apur.Sansar.1959.mkv"
+        ]
+    );
+    // Nothing is left behind with the journals but the undone runs
+    for entry in fs::read_dir(env.root.join("state/movie-rename")).unwrap() {
+        assert!(entry
+            .unwrap()
+            .path()
+            .extension()
+            .is_some_and(|e| e == "undone"));
+    }
+}
+
+#[test]
+fn downloads_the_artwork() {
+    let server = MockTmdb::start(movies());
+    let config = format!(
+        "[artwork]\nbase_url = \"{}/t/p\"\nposter_size = \"w500\"",
+        server.address
+    );
+    let env = TestEnv::new("artwork", &server, &config);
+    env.touch(&["Apu/Apur.Sansar.1959.mkv", "Apu/fanart.jpg"]);
+
+    let output = env.run(&["-l", "-d", "--artwork", "Apu"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("'fanart.jpg' already exists, skipping"));
+    let dir = "Apur Sansar (1959) - Satyajit Ray";
+    assert_eq!(
+        env.files(),
+        [
+            format!("{dir}/{dir}.mkv"),
+            format!("{dir}/fanart.jpg"),
+            format!("{dir}/poster.jpg")
+        ]
+    );
+    let poster = std::fs::read_to_string(env.root.join("files").join(dir).join("poster.jpg"));
+    assert_eq!(poster.unwrap(), "/t/p/w500/poster-25587.jpg");
+
+    // Only the downloaded images are removed when undoing
+    env.run(&["--undo"]);
+    assert_eq!(env.files(), ["Apu/Apur.Sansar.1959.mkv", "Apu/fanart.jpg"]);
+}
+
+#[test]
+fn names_the_artwork_after_the_movie_in_shared_directories() {
+    let server = MockTmdb::start(movies());
+    let config = format!("[artwork]\nbase_url = \"{}/t/p\"", server.address);
+    let env = TestEnv::new("artwork-shared", &server, &config);
+    env.touch(&["Apur.Sansar.1959.mkv", "Mission.Impossible.1996.mkv"]);
+
+    let output = env.run(&["-l", "--artwork", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray-fanart.jpg",
+            "Apur Sansar (1959) - Satyajit Ray-poster.jpg",
+            "Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Mission.Impossible.1996.mkv"
+        ]
+    );
+    assert!(server
+        .requests()
+        .contains(&String::from("/t/p/w1280/backdrop-25587.jpg")));
+}
+
+#[test]
+fn moves_files_into_the_library() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("library", &server, "");
+    env.touch(&["Apu/Apur.Sansar.1959.mkv", "Apu/Apur.Sansar.1959.en.srt"]);
+
+    let output = env.run(&["-l", "-d", "--library-root", "library", "Apu"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "library/Apur Sansar (1959)/Apur Sansar (1959) - Satyajit Ray.en.srt",
+            "library/Apur Sansar (1959)/Apur Sansar (1959) - Satyajit Ray.mkv"
+        ]
+    );
+
+    // The directories created for the movie are removed too
+    env.run(&["--undo"]);
+    assert_eq!(
+        env.files(),
+        ["Apu/Apur.Sansar.1959.en.srt", "Apu/Apur.Sansar.1959.mkv"]
+    );
+    assert!(!env.path("library").exists());
+}
+
+#[test]
+fn copies_files_into_the_library() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("copy", &server, "");
+    env.touch(&["Apu/Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&[
+        "-l",
+        "-d",
+        "--mode",
+        "copy",
+        "--library-root",
+        "library",
+        "Apu",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apu/Apur.Sansar.1959.mkv",
+            "library/Apur Sansar (1959)/Apur Sansar (1959) - Satyajit Ray.mkv"
+        ]
+    );
+
+    // Only the copies are removed when undoing
+    env.run(&["--undo"]);
+    assert_eq!(env.files(), ["Apu/Apur.Sansar.1959.mkv"]);
+}
+
+#[test]
+fn links_files_instead_of_renaming() {
+    use std::os::unix::fs::MetadataExt;
+
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("link", &server, "");
+    env.touch(&["Apu/Apur.Sansar.1959.mkv"]);
+
+    // The directory keeps its name too, since the files in it are still used
+    let output = env.run(&["-l", "-d", "--mode", "hardlink", "Apu"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apu/Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Apu/Apur.Sansar.1959.mkv"
+        ]
+    );
+    let original = env.path("Apu/Apur.Sansar.1959.mkv").metadata().unwrap();
+    let link = env
+        .path("Apu/Apur Sansar (1959) - Satyajit Ray.mkv")
+        .metadata()
+        .unwrap();
+    assert_eq!(original.ino(), link.ino());
+
+    env.run(&["--undo"]);
+    assert_eq!(env.files(), ["Apu/Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "--mode", "symlink", "Apu/Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.path("Apu/Apur Sansar (1959) - Satyajit Ray.mkv")
+            .read_link()
+            .unwrap(),
+        env.path("Apu/Apur.Sansar.1959.mkv")
+    );
+}
+
+#[test]
+fn skips_or_suffixes_taken_names() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("conflict", &server, "");
+    env.touch(&[
+        "Apur.Sansar.1959.mkv",
+        "Apur Sansar (1959) - Satyajit Ray.mkv",
+    ]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Destination file already exists, skipping"));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Apur.Sansar.1959.mkv"
+        ]
+    );
+
+    let output = env.run(&["-l", "--on-conflict", "suffix", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray (1).mkv",
+            "Apur Sansar (1959) - Satyajit Ray.mkv"
+        ]
+    );
+}
+
+#[test]
+fn never_replaces_the_source_with_itself() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("same-file", &server, "");
+    fs::write(env.path("Apur.Sansar.1959.mkv"), "movie").unwrap();
+
+    let output = env.run(&["-l", "--mode", "hardlink", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // The destination is a hardlink to the source, so overwriting it with a copy
+    // must leave both of them alone
+    let args = ["-l", "--mode", "copy", "--on-conflict", "overwrite"];
+    let output = env.run(&[&args[..], &["Apur.Sansar.1959.mkv"]].concat());
+    assert!(stderr(&output).contains("the destination is the same file as the source"));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Apur.Sansar.1959.mkv"
+        ]
+    );
+    for file in env.files() {
+        assert_eq!(fs::read_to_string(env.path(&file)).unwrap(), "movie");
+    }
+}
+
+#[test]
+fn keeps_the_better_file() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("keep-better", &server, "");
+    let dest = "Apur Sansar (1959) - Satyajit Ray.mkv";
+    let write = |name: &str, contents: &str| fs::write(env.path(name), contents).unwrap();
+    write(dest, "small");
+    write("Apur.Sansar.1959.720p.BluRay.mkv", "larger file");
+
+    let args = ["-l", "--on-conflict", "keep-larger"];
+    let output = env.run(&[&args[..], &["Apur.Sansar.1959.720p.BluRay.mkv"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), [dest]);
+    assert_eq!(fs::read_to_string(env.path(dest)).unwrap(), "larger file");
+
+    // The existing file was a 720p one as per the journal, so a smaller 1080p one
+    // replaces it, and a 480p one doesn't
+    write("Apur.Sansar.1959.1080p.WEB-DL.mkv", "1080p");
+    write("Apur.Sansar.1959.480p.mkv", "the largest file");
+    let args = ["-l", "--on-conflict", "keep-better-quality"];
+    let output = env.run(&[&args[..], &["Apur.Sansar.1959.1080p.WEB-DL.mkv"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read_to_string(env.path(dest)).unwrap(), "1080p");
+
+    let output = env.run(&[&args[..], &["Apur.Sansar.1959.480p.mkv"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("The existing file is at least as good, skipping"));
+    assert_eq!(env.files(), [dest, "Apur.Sansar.1959.480p.mkv"]);
+}
+
+#[test]
+fn names_the_nfo_file_movie_nfo_in_movie_directories() {
+    let server = MockTmdb::start(movies());
+    let config = "library_root = \"library\"\nmovie_nfo = true";
+    let env = TestEnv::new("movie-nfo", &server, config);
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "--nfo", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "library/Apur Sansar (1959)/Apur Sansar (1959) - Satyajit Ray.mkv",
+            "library/Apur Sansar (1959)/movie.nfo"
+        ]
+    );
+}
+
+#[test]
+fn uses_the_folder_pattern() {
+    let server = MockTmdb::start(movies());
+    let config = "library_root = \"library\"\nfolder_pattern = \"[{collection}/]{title} ({year})\"\npattern = \"{title} ({year})\"";
+    let env = TestEnv::new("folder-pattern", &server, config);
+    env.touch(&["Apur.Sansar.1959.mkv", "Knockin.on.Heavens.Door.1997.mkv"]);
+
+    let output = env.run(&[
+        "-l",
+        "--nfo",
+        "Apur.Sansar.1959.mkv",
+        "Knockin.on.Heavens.Door.1997.mkv",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "library/Knockin' on Heaven's Door (1997)/Knockin' on Heaven's Door (1997).mkv",
+            "library/Knockin' on Heaven's Door (1997)/Knockin' on Heaven's Door (1997).nfo",
+            "library/The Apu Trilogy/Apur Sansar (1959)/Apur Sansar (1959).mkv",
+            "library/The Apu Trilogy/Apur Sansar (1959)/Apur Sansar (1959).nfo"
+        ]
+    );
+}
+
+#[test]
+fn skips_unconfident_matches_without_a_terminal() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("threshold", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv", "Mission.1996.mkv"]);
+
+    let output = env.run(&["-a", "0.9", "Apur.Sansar.1959.mkv", "Mission.1996.mkv"]);
+    assert!(stderr(&output).contains("Could not find a confident match for Mission.1996.mkv"));
+    assert_eq!(
+        env.files(),
+        ["Apur Sansar (1959) - Satyajit Ray.mkv", "Mission.1996.mkv"]
+    );
+}
+
+#[test]
+fn leaves_out_the_subtitle_language_without_a_terminal() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("threshold-subtitles", &server, "");
+    env.touch(&["Apur.Sansar.1959.srt", "Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-a", "0.5", "Apur.Sansar.1959.srt", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("Could not ask for the language of Apur.Sansar.1959.srt"));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Apur Sansar (1959) - Satyajit Ray.srt"
+        ]
+    );
+}
+
+#[test]
+fn writes_and_applies_a_plan() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("plan", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv", "Knockin.on.Heavens.Door.1997.mkv"]);
+    let plan_file = env.root.join("plan.json").display().to_string();
+
+    let output = env.run(&[
+        "--plan",
+        &plan_file,
+        "Apur.Sansar.1959.mkv",
+        "Knockin.on.Heavens.Door.1997.mkv",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Nothing is renamed while planning
+    assert_eq!(
+        env.files(),
+        ["Apur.Sansar.1959.mkv", "Knockin.on.Heavens.Door.1997.mkv"]
+    );
+    let mut plan: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&plan_file).unwrap()).unwrap();
+    let files = plan["files"].as_array().unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0]["choice"], 0);
+    assert_eq!(
+        files[0]["candidates"][0]["new_name"],
+        "Apur Sansar (1959) - Satyajit Ray.mkv"
+    );
+
+    // Files without a choice are left alone
+    plan["files"][1]["choice"] = serde_json::Value::Null;
+    fs::write(&plan_file, plan.to_string()).unwrap();
+    let output = env.run(&["--apply", &plan_file]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        env.files(),
+        [
+            "Apur Sansar (1959) - Satyajit Ray.mkv",
+            "Knockin.on.Heavens.Door.1997.mkv"
+        ]
+    );
+}
+
+#[test]
+fn applies_a_plan_after_the_files_changed() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("plan-changed", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv", "Knockin.on.Heavens.Door.1997.mkv"]);
+    let plan_file = env.root.join("plan.json").display().to_string();
+
+    let output = env.run(&[
+        "--plan",
+        &plan_file,
+        "Apur.Sansar.1959.mkv",
+        "Knockin.on.Heavens.Door.1997.mkv",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // One of the sources is gone, and the destination of the other is taken
+    fs::rename(env.path("Apur.Sansar.1959.mkv"), env.path("apu.mkv")).unwrap();
+    env.touch(&["Knockin' on Heaven's Door (1997) - Thomas Jahn and Til Schweiger.mkv"]);
+
+    let output = env.run(&["--apply", &plan_file]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Apur.Sansar.1959.mkv wasn't found on disk, skipping"));
+    assert!(stderr(&output).contains("Destination file already exists, skipping"));
+    assert_eq!(
+        env.files(),
+        [
+            "Knockin' on Heaven's Door (1997) - Thomas Jahn and Til Schweiger.mkv",
+            "Knockin.on.Heavens.Door.1997.mkv",
+            "apu.mkv"
+        ]
+    );
+}
+
+#[test]
+fn needs_an_absolute_directory_for_the_cache_and_the_journal() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("no-home", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    // The cache is left out, instead of being put inside the current directory
+    let output = env
+        .command(&["-l", "Apur.Sansar.1959.mkv"])
+        .env("HOME", "")
+        .env_remove("XDG_CACHE_HOME")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Could not find a directory for the cache"));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+
+    let output = env
+        .command(&["--undo"])
+        .env("HOME", "")
+        .env_remove("XDG_STATE_HOME")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("Could not find a directory for the journal"));
+}
+
+#[test]
+fn retries_when_rate_limited() {
+    let server = MockTmdb::start_failing(movies(), 2);
+    let env = TestEnv::new("retry", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(stderr(&output).contains("rate limited by TMDB (HTTP 429)"));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn fetches_the_credits_of_the_chosen_movie_only_when_lazy() {
+    // A second match for the search, which isn't chosen
+    let mut movies = movies();
+    movies.push(Movie {
+        id: 1,
+        title: "Apur Sansar Revisited",
+        original_title: "Apur Sansar Revisited",
+        original_language: "en",
+        release_date: "1959-10-01",
+        directors: vec!["Someone Else"],
+        imdb_id: "tt0000001",
+        collection: None,
+        runtime: 30,
+        overview: "",
+        genres: vec![],
+        translations: vec![],
+    });
+    let server = MockTmdb::start(movies);
+    let env = TestEnv::new("lazy-credits", &server, "lazy_credits = true");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+    let credits: Vec<String> = server
+        .requests()
+        .into_iter()
+        .filter(|r| r.contains("/credits"))
+        .collect();
+    assert_eq!(credits.len(), 1, "{credits:?}");
+    assert!(credits[0].starts_with("/3/movie/25587/credits"));
+}
+
+#[test]
+fn reuses_the_cached_responses() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("cache", &server, "");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    env.run(&["-ln", "Apur.Sansar.1959.mkv"]);
+    let requests = server.requests().len();
+    let output = env.run(&["-lo", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(server.requests().len(), requests);
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn works_with_the_fixture_provider() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new(
+        "fixture",
+        &server,
+        "provider = \"fixture\"\nfixture = \"../movies.json\"",
+    );
+    env.write(
+        "movies.json",
+        r#"[{"id": 25587, "title": "Apur Sansar", "release_date": "1959-05-01", "directors": ["Satyajit Ray"]}]"#,
+    );
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(server.requests().is_empty());
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn goes_through_the_proxy() {
+    let server = MockTmdb::start(movies());
+    let proxy = format!("[http]\nproxy = \"{}\"", server.address);
+    let mut env = TestEnv::new("proxy", &server, &proxy);
+    // The host doesn't exist, so this only works if the proxy is used
+    env.base_url = String::from("http://tmdb.invalid/3");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!server.requests().is_empty());
+    assert_eq!(env.files(), ["Apur Sansar (1959) - Satyajit Ray.mkv"]);
+}
+
+#[test]
+fn reports_timeouts() {
+    // A server that accepts the connections, but never answers
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let _connections: Vec<_> = listener.incoming().collect();
+    });
+
+    let server = MockTmdb::start(movies());
+    let mut env = TestEnv::new(
+        "timeout",
+        &server,
+        "max_retries = 0\n[http]\ntimeout_secs = 1",
+    );
+    env.base_url = format!("http://{address}/3");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert!(stderr(&output).contains("the request timed out"));
+    assert_eq!(env.files(), ["Apur.Sansar.1959.mkv"]);
+}
+
+#[test]
+fn rejects_an_invalid_proxy() {
+    let server = MockTmdb::start(movies());
+    let env = TestEnv::new("bad-proxy", &server, "[http]\nproxy = \"not a url\"");
+    env.touch(&["Apur.Sansar.1959.mkv"]);
+
+    let output = env.run(&["-l", "Apur.Sansar.1959.mkv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("Invalid proxy 'not a url'"));
+}
+
+#[test]
+fn uses_the_localized_title() {
+    let server = MockTmdb::start(movies());
+    let config = "pattern = \"{localized_title} ({year})\"\nlanguage = \"fr-CA\"";
+    let env = TestEnv::new("localized", &server, config);
+    env.touch(&["Mission.Impossible.1996.mkv", "Apur.Sansar.1959.mkv"]);
+
+    env.run(&["-l", "Mission.Impossible.1996.mkv", "Apur.Sansar.1959.mkv"]);
+    // There's no French title for Apur Sansar, so the English one is used
+    assert_eq!(
+        env.files(),
+        [
+            "Mission impossible (1996).mkv",
+            "The World of Apu (1959).mkv"
+        ]
+    );
+    assert!(server
+        .requests()
+        .iter()
+        .any(|r| r.contains("language=fr-CA")));
+}
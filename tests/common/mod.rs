@@ -0,0 +1,453 @@
+// Helpers for running the binary against a local stand-in for the TMDB API
+#![allow(dead_code)]
+
+use serde_json::{json, Value};
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+// A movie known to the mock server
+pub struct Movie {
+    pub id: u64,
+    pub title: &'static str,
+    pub original_title: &'static str,
+    pub original_language: &'static str,
+    pub release_date: &'static str,
+    pub directors: Vec<&'static str>,
+    pub imdb_id: &'static str,
+    pub collection: Option<&'static str>,
+    pub runtime: u64,
+    pub overview: &'static str,
+    pub genres: Vec<&'static str>,
+    // Language, region and title of the translations
+    pub translations: Vec<(&'static str, &'static str, &'static str)>,
+}
+
+// The movies used by most of the tests
+pub fn movies() -> Vec<Movie> {
+    vec![
+        Movie {
+            id: 25587,
+            title: "Apur Sansar",
+            original_title: "অপুর সংসার",
+            original_language: "bn",
+            release_date: "1959-05-01",
+            directors: vec!["Satyajit Ray"],
+            imdb_id: "tt0052572",
+            collection: Some("The Apu Trilogy"),
+            runtime: 117,
+            overview: "Apu, an unemployed graduate, unexpectedly ends up married & moves to Calcutta with his bride.",
+            genres: vec!["Drama"],
+            translations: vec![
+                ("bn", "IN", "অপুর সংসার"),
+                ("en", "US", "The World of Apu"),
+                ("de", "DE", "Apus Weg ins Leben: Apur Sansar"),
+            ],
+        },
+        Movie {
+            id: 954,
+            title: "Mission: Impossible",
+            original_title: "Mission: Impossible",
+            original_language: "en",
+            release_date: "1996-05-22",
+            directors: vec!["Brian De Palma"],
+            imdb_id: "tt0117060",
+            collection: Some("Mission: Impossible Collection"),
+            runtime: 110,
+            overview: "When Ethan Hunt is framed for the deaths of his team, he goes looking for the real mole.",
+            genres: vec!["Action", "Thriller"],
+            translations: vec![
+                ("en", "US", "Mission: Impossible"),
+                ("fr", "FR", "Mission : Impossible"),
+                ("fr", "CA", "Mission impossible"),
+            ],
+        },
+        Movie {
+            id: 5072,
+            title: "Knockin' on Heaven's Door",
+            original_title: "Knockin' on Heaven's Door",
+            original_language: "de",
+            release_date: "1997-02-20",
+            directors: vec!["Thomas Jahn", "Til Schweiger"],
+            imdb_id: "tt0119472",
+            collection: None,
+            runtime: 87,
+            overview: "Two terminally ill men escape from the hospital to see the sea.",
+            genres: vec!["Comedy", "Crime"],
+            translations: vec![("de", "DE", "Knockin' on Heaven's Door")],
+        },
+    ]
+}
+
+// A local HTTP server answering like the TMDB API
+pub struct MockTmdb {
+    pub url: String,
+    // Address of the server, for using it as a proxy
+    pub address: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockTmdb {
+    pub fn start(movies: Vec<Movie>) -> MockTmdb {
+        MockTmdb::start_failing(movies, 0)
+    }
+
+    // Start a server that answers the first few requests with HTTP 429
+    pub fn start_failing(movies: Vec<Movie>, failures: usize) -> MockTmdb {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to start the mock server");
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let url = format!("{address}/3");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let count = log.lock().unwrap().len();
+                let Some(path) = read_request(&stream) else {
+                    continue;
+                };
+                log.lock().unwrap().push(path.clone());
+                // The images have their path as their contents
+                if path.starts_with("/t/p/") {
+                    write_raw(stream, "200 OK", "image/jpeg", path.as_bytes());
+                    continue;
+                }
+                let (status, body) = if count < failures {
+                    (
+                        "429 Too Many Requests",
+                        json!({"status_code": 25, "status_message": "Too many requests."}),
+                    )
+                } else {
+                    respond(&movies, &path)
+                };
+                write_response(stream, status, &body);
+            }
+        });
+
+        MockTmdb {
+            url,
+            address,
+            requests,
+        }
+    }
+
+    // Paths of the requests received so far
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Read the request line and skip the headers, returning the path
+fn read_request(stream: &TcpStream) -> Option<String> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    // Requests sent through a proxy have the full URL in them
+    let target = request_line.split_whitespace().nth(1)?;
+    match target.strip_prefix("http://") {
+        Some(url) => url.find('/').map(|i| url[i..].to_string()),
+        None => Some(target.to_string()),
+    }
+}
+
+fn write_response(stream: TcpStream, status: &str, body: &Value) {
+    write_raw(
+        stream,
+        status,
+        "application/json",
+        body.to_string().as_bytes(),
+    );
+}
+
+fn write_raw(mut stream: TcpStream, status: &str, content_type: &str, body: &[u8]) {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).ok();
+    stream.write_all(body).ok();
+}
+
+// Answer a request for the API, the same way TMDB does
+fn respond(movies: &[Movie], path: &str) -> (&'static str, Value) {
+    let (route, query) = path.split_once('?').unwrap_or((path, ""));
+    let params: Vec<(String, String)> = query
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .map(|(k, v)| (k.to_string(), decode(v)))
+        .collect();
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    };
+    let parts: Vec<&str> = route.trim_start_matches("/3/").split('/').collect();
+
+    match parts.as_slice() {
+        ["search", "movie"] => {
+            let query = normalize(param("query").unwrap_or_default());
+            let results: Vec<Value> = movies
+                .iter()
+                .filter(|m| normalize(m.title).contains(&query))
+                .filter(|m| param("year").is_none_or(|y| m.release_date.starts_with(y)))
+                .map(|m| movie_base(m, json!({"genre_ids": []})))
+                .collect();
+            let count = results.len();
+            let body =
+                json!({"page": 1, "total_results": count, "total_pages": 1, "results": results});
+            ("200 OK", body)
+        }
+        ["find", imdb_id] => {
+            let results: Vec<Value> = movies
+                .iter()
+                .filter(|m| m.imdb_id == *imdb_id && param("external_source") == Some("imdb_id"))
+                .map(|m| movie_base(m, json!({"genre_ids": [], "media_type": "movie"})))
+                .collect();
+            let body = json!({"movie_results": results, "person_results": [], "tv_results": []});
+            ("200 OK", body)
+        }
+        ["movie", id, rest @ ..] => {
+            let Some(movie) = movies.iter().find(|m| m.id.to_string() == *id) else {
+                return (
+                    "404 Not Found",
+                    json!({"status_code": 34, "status_message": "The resource could not be found."}),
+                );
+            };
+            match rest {
+                ["credits"] => ("200 OK", credits(movie)),
+                ["translations"] => ("200 OK", translations(movie)),
+                _ => ("200 OK", details(movie)),
+            }
+        }
+        _ => (
+            "404 Not Found",
+            json!({"status_code": 34, "status_message": "The resource could not be found."}),
+        ),
+    }
+}
+
+// The fields shared by the search results and the details
+fn movie_base(movie: &Movie, extra: Value) -> Value {
+    let mut value = json!({
+        "id": movie.id,
+        "title": movie.title,
+        "original_title": movie.original_title,
+        "original_language": movie.original_language,
+        "overview": movie.overview,
+        "release_date": movie.release_date,
+        "poster_path": format!("/poster-{}.jpg", movie.id),
+        "backdrop_path": format!("/backdrop-{}.jpg", movie.id),
+        "adult": false,
+        "popularity": 10.0,
+        "vote_count": 100,
+        "vote_average": 8.0,
+        "video": false,
+    });
+    value
+        .as_object_mut()
+        .unwrap()
+        .extend(extra.as_object().unwrap().clone());
+    value
+}
+
+fn credits(movie: &Movie) -> Value {
+    let crew: Vec<Value> = movie
+        .directors
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            json!({
+                "credit_id": format!("credit-{i}"),
+                "adult": false,
+                "known_for_department": "Directing",
+                "original_name": name,
+                "popularity": 1.0,
+                "id": i,
+                "name": name,
+                "gender": null,
+                "profile_path": null,
+                "department": "Directing",
+                "job": "Director",
+            })
+        })
+        .collect();
+    json!({"id": movie.id, "cast": [], "crew": crew})
+}
+
+fn translations(movie: &Movie) -> Value {
+    let translations: Vec<Value> = movie
+        .translations
+        .iter()
+        .map(|(language, region, title)| {
+            json!({
+                "iso_3166_1": region,
+                "iso_639_1": language,
+                "name": language,
+                "english_name": language,
+                "data": {"title": title, "overview": "", "homepage": ""},
+            })
+        })
+        .collect();
+    json!({"id": movie.id, "translations": translations})
+}
+
+fn details(movie: &Movie) -> Value {
+    let collection = movie.collection.map(|name| {
+        json!({"id": 1, "name": name, "overview": null, "poster_path": null, "backdrop_path": null})
+    });
+    movie_base(
+        movie,
+        json!({
+            "budget": 0,
+            "genres": movie
+                .genres
+                .iter()
+                .enumerate()
+                .map(|(i, name)| json!({"id": i, "name": name}))
+                .collect::<Vec<_>>(),
+            "homepage": "",
+            "imdb_id": movie.imdb_id,
+            "belongs_to_collection": collection,
+            "production_companies": [],
+            "production_countries": [],
+            "revenue": 0,
+            "runtime": movie.runtime,
+            "spoken_languages": [],
+            "status": "Released",
+            "tagline": null,
+        }),
+    )
+}
+
+// Decode a URL encoded query parameter
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                out.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+                i += 2;
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+// Compare only the letters and digits of titles
+fn normalize(title: &str) -> String {
+    title
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect()
+}
+
+// A temporary directory with its own config, cache and state, for running the binary
+pub struct TestEnv {
+    pub root: PathBuf,
+    pub base_url: String,
+}
+
+impl TestEnv {
+    // Create the directory, with the given lines added to the config file
+    pub fn new(name: &str, server: &MockTmdb, config: &str) -> TestEnv {
+        let root = env::temp_dir().join(format!("movie-rename-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        fs::create_dir_all(root.join("config/movie-rename")).unwrap();
+        fs::create_dir_all(root.join("files")).unwrap();
+        fs::write(
+            root.join("config/movie-rename/config.toml"),
+            format!("api_key = \"test\"\n{config}\n"),
+        )
+        .unwrap();
+        TestEnv {
+            root,
+            base_url: server.url.clone(),
+        }
+    }
+
+    // Create empty files inside the files directory, along with their parents
+    pub fn touch(&self, paths: &[&str]) {
+        for path in paths {
+            let path = self.root.join("files").join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+    }
+
+    // Write a file outside the files directory, like a fixture
+    pub fn write(&self, path: &str, contents: &str) {
+        fs::write(self.root.join(path), contents).unwrap();
+    }
+
+    // Run the binary inside the files directory, without a terminal
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.command(args)
+            .output()
+            .expect("Unable to run movie-rename")
+    }
+
+    // The command for running the binary, for changing it before it's run
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_movie-rename"));
+        command
+            .args(args)
+            .current_dir(self.root.join("files"))
+            .env("HOME", &self.root)
+            .env("XDG_CONFIG_HOME", self.root.join("config"))
+            .env("XDG_CACHE_HOME", self.root.join("cache"))
+            .env("XDG_STATE_HOME", self.root.join("state"))
+            .env("MOVIE_RENAME_BASE_URL", &self.base_url)
+            .stdin(Stdio::null());
+        command
+    }
+
+    // Path of an entry inside the files directory
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join("files").join(path)
+    }
+
+    // All the files inside the files directory, relative to it and sorted
+    pub fn files(&self) -> Vec<String> {
+        let mut files = Vec::new();
+        list(&self.root.join("files"), Path::new(""), &mut files);
+        files.sort();
+        files
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.root).ok();
+    }
+}
+
+fn list(dir: &Path, prefix: &Path, files: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap().flatten() {
+        let path = prefix.join(entry.file_name());
+        if entry.path().is_dir() {
+            list(&entry.path(), &path, files);
+        } else {
+            files.push(path.display().to_string());
+        }
+    }
+}